@@ -0,0 +1,1050 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::smart_ess::usage::UnitUsage;
+use crate::smart_ess::{Controller, ControllerError, ControllerInputState, ControllerOutputState};
+use crate::victron::client::{Backoff, VictronConnection};
+use crate::victron::ess::{self, Drift, Hub4Mode, VictronESS};
+use crate::victron::ve_battery::{BatteryState, VictronBattery};
+use crate::victron::ve_bus::{Mode, VictronBus};
+use crate::victron::ve_system::{SystemSnapshot, VictronSystem};
+use crate::victron::{Line, VictronError};
+use crate::control::safety::{Interlock, SafetyRule};
+use crate::control::shutdown::Shutdown;
+
+pub mod safety;
+pub mod shutdown;
+
+#[derive(Debug)]
+pub enum ControlError {
+    /// Reading from or writing to the GX failed
+    Device(VictronError),
+
+    /// Controller could not produce a desired state
+    Controller(ControllerError),
+
+    /// The GX would ignore the ESS registers written by the loop
+    NotControllable(Capability),
+}
+
+impl Display for ControlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::Device(e) => write!(f, "Device error: {}", e),
+            ControlError::Controller(e) => write!(f, "Controller error: {}", e),
+            ControlError::NotControllable(c) => write!(f, "External control not possible: {}", c),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ControlError::Device(e) => Some(e),
+            ControlError::Controller(e) => Some(e),
+            ControlError::NotControllable(_) => None,
+        }
+    }
+}
+
+impl From<VictronError> for ControlError {
+    fn from(e: VictronError) -> Self {
+        ControlError::Device(e)
+    }
+}
+
+impl From<ControllerError> for ControlError {
+    fn from(e: ControllerError) -> Self {
+        ControlError::Controller(e)
+    }
+}
+
+/// Control loop sections of the config file, defaults are used for missing sections
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub safety: Interlock,
+
+    #[serde(default)]
+    pub shutdown: Shutdown,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, ControllerError> {
+        let mut json = String::new();
+        File::open(path)?.read_to_string(&mut json)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+/// Modbus unit ids of the devices behind the GX
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Units {
+    pub inverter: u8,
+    pub battery: u8,
+    pub system: u8,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Self {
+            inverter: 227,
+            battery: 225,
+            system: 100,
+        }
+    }
+}
+
+/// Site parameters which are not part of the rate config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Params {
+    /// Battery capacity in kWh, used when the battery monitor is unavailable
+    pub capacity: f32,
+
+    /// Nominal battery voltage, converts the battery monitor capacity in Ah to kWh
+    pub nominal_voltage: f32,
+
+    /// The grid meter balances the setpoint over all phases, as set in the
+    /// GX multiphase regulation. The Hub4 mode can't tell us this since the
+    /// loop only runs in external control
+    pub phase_compensation: bool,
+
+    /// Lowest grid setpoint written to the ESS in watts, not applied while
+    /// exporting on purpose
+    pub min_set_point: i16,
+
+    /// How often the ESS registers are read back to detect changes made
+    /// elsewhere, unchanged registers are also written again at this interval
+    /// so the Multi stays in external control
+    pub verify_interval: Duration,
+
+    /// How often dynamic tariff prices are fetched
+    pub tariff_refresh: Duration,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            capacity: 7.2,
+            nominal_voltage: 48.0,
+            phase_compensation: false,
+            min_set_point: 50,
+            verify_interval: Duration::try_seconds(30).unwrap(),
+            tariff_refresh: Duration::try_minutes(30).unwrap(),
+        }
+    }
+}
+
+/// ESS settings which decide whether the GX acts on our register writes
+#[derive(Debug, Clone)]
+pub struct Capability {
+    pub hub4_mode: Hub4Mode,
+    pub bus_mode: Mode,
+
+    /// ESS minimum state of charge percent, [None] if it could not be read
+    pub min_soc: Option<f32>,
+}
+
+impl Capability {
+    /// The setpoint and charge/feed-in registers are only used in external
+    /// control mode with the inverter switched on
+    pub fn honoured(&self) -> bool {
+        self.hub4_mode == Hub4Mode::External && self.bus_mode == Mode::On
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ESS mode: {}, VE.Bus mode: {}", self.hub4_mode, self.bus_mode)?;
+        if let Some(soc) = self.min_soc {
+            write!(f, ", Min SoC: {}%", soc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the system state, asks the [Controller] what to do and writes the
+/// result to the ESS registers
+pub struct ControlLoop {
+    controller: Controller,
+    interlock: Interlock,
+    shutdown: Shutdown,
+    params: Params,
+    bus: VictronBus,
+    ess: VictronESS,
+    battery: VictronBattery,
+    system: VictronSystem,
+
+    /// ESS registers as they were before we took control
+    prior: Option<Vec<ess::Register>>,
+
+    /// ESS mode before [ControlLoop::check] switched to external control
+    prior_mode: Option<Hub4Mode>,
+
+    /// Time of the last ESS read-back
+    verified: Option<DateTime<Utc>>,
+
+    /// Time dynamic tariff prices were last fetched
+    priced: Option<DateTime<Utc>>,
+
+    /// Failed price fetches since the last one that worked, and the time
+    /// the next attempt is due
+    price_failures: u32,
+    price_retry: Option<DateTime<Utc>>,
+
+    /// Grid energy used for charging in the current rate window
+    usage: UnitUsage,
+
+    /// File the usage is saved to, not saved without one
+    usage_path: Option<String>,
+
+    /// Time the usage was last saved
+    usage_saved: Option<DateTime<Utc>>,
+}
+
+/// Longest time between usage saves within a rate window, well inside the
+/// gap [UnitUsage] still counts after a restart
+fn usage_save_interval() -> Duration {
+    Duration::try_minutes(1).unwrap()
+}
+
+/// Result of a single [ControlLoop::tick]
+#[derive(Debug, Clone)]
+pub struct Tick {
+    /// State written to the ESS, after safety rules were applied
+    pub state: ControllerOutputState,
+
+    /// Grid setpoints written for L1-L3
+    pub set_points: Vec<i16>,
+
+    /// ESS registers changed by someone else since they were written
+    pub drift: Vec<Drift>,
+
+    /// Safety rules which overrode the controller
+    pub triggered: Vec<SafetyRule>,
+
+    /// Battery monitor state, [None] if the configured capacity was used
+    pub battery: Option<BatteryState>,
+
+    /// System readings, [None] if the inverter output was used as the load
+    pub system: Option<SystemSnapshot>,
+
+    /// Saving the charge usage failed, the count is kept in memory
+    pub usage_error: Option<String>,
+}
+
+impl Display for Tick {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.state)?;
+        if self.set_points.len() > 1 {
+            write!(f, "\nSetpoints: {:?} W", self.set_points)?;
+        }
+        if let Some(plan) = &self.state.plan {
+            write!(f, "\nPlan cost: {:.2}", plan.cost())?;
+            for step in plan.steps.iter().take(4) {
+                write!(f, "\nPlan: {}", step)?;
+            }
+        }
+        if self.state.current_rate.rate.charge.charge_enabled() {
+            write!(f, "\nCharged: {:.2} kWh this window", self.state.window_units)?;
+        }
+        if let Some(s) = &self.system {
+            write!(
+                f,
+                "\nSystem: {:.0}W load, {:.0}W grid, {:.0}W PV",
+                s.consumption_power(),
+                s.grid_power(),
+                s.pv_power()
+            )?;
+        }
+        match &self.battery {
+            Some(b) => write!(
+                f,
+                "\nBattery: {:.2}V {:.1}A {:.1}C, {:.1}Ah used of {:.1}Ah",
+                b.voltage, b.current, b.temperature, b.consumed_ah, b.capacity
+            )?,
+            None => write!(f, "\nBattery: unavailable, using configured capacity")?,
+        }
+        for d in &self.drift {
+            write!(f, "\nDrift: {}", d)?;
+        }
+        for rule in &self.triggered {
+            write!(f, "\nSafety: {} ({:?})", rule.trigger, rule.level)?;
+        }
+        if let Some(e) = &self.usage_error {
+            write!(f, "\nFailed to save usage: {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl ControlLoop {
+    pub fn new(controller: Controller, conn: &VictronConnection, units: Units) -> Self {
+        Self {
+            controller,
+            interlock: Interlock::default(),
+            shutdown: Shutdown::default(),
+            params: Params::default(),
+            bus: VictronBus::new(conn, units.inverter),
+            ess: VictronESS::new(conn, units.inverter, units.system),
+            battery: VictronBattery::new(conn, units.battery),
+            system: VictronSystem::new(conn, units.system),
+            prior: None,
+            prior_mode: None,
+            verified: None,
+            priced: None,
+            price_failures: 0,
+            price_retry: None,
+            usage: UnitUsage::default(),
+            usage_path: None,
+            usage_saved: None,
+        }
+    }
+
+    pub fn set_params(&mut self, params: Params) {
+        self.ess.set_max_age(params.verify_interval);
+        self.params = params;
+    }
+
+    pub fn set_interlock(&mut self, interlock: Interlock) {
+        self.interlock = interlock;
+    }
+
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        self.interlock = config.safety;
+        self.shutdown = config.shutdown;
+    }
+
+    /// Load the charge usage from `path` and save it there when the rate
+    /// window changes and every [usage_save_interval]. If the saved usage
+    /// can't be loaded it starts empty and the error is returned as a warning
+    pub fn set_usage_path(&mut self, path: &str) -> Result<(), ControlError> {
+        self.usage_path = Some(path.to_owned());
+        match UnitUsage::load(path) {
+            Ok(usage) => {
+                self.usage = usage;
+                Ok(())
+            }
+            Err(e) => {
+                self.usage = UnitUsage::default();
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Fetch dynamic tariff prices when they are due, returns the number of
+    /// slots or [None] if nothing was fetched. Failed fetches are retried
+    /// with a backoff from one minute up to the refresh interval
+    pub async fn refresh_prices(&mut self, now: DateTime<Utc>) -> Result<Option<usize>, ControlError> {
+        if self.controller.tariff().is_none() {
+            return Ok(None);
+        }
+        let due = match self.price_retry {
+            Some(t) => now >= t,
+            None => !matches!(self.priced, Some(t) if now - t < self.params.tariff_refresh),
+        };
+        if !due {
+            return Ok(None);
+        }
+        match self.controller.update_prices().await {
+            Ok(n) => {
+                self.priced = Some(now);
+                self.price_failures = 0;
+                self.price_retry = None;
+                Ok(Some(n))
+            }
+            Err(e) => {
+                self.price_failures += 1;
+                let mut backoff = Backoff::default();
+                backoff.initial = std::time::Duration::from_secs(60);
+                backoff.max = self.params.tariff_refresh.to_std().unwrap_or_default();
+                let delay = backoff.delay(self.price_failures);
+                self.price_retry = Some(now + Duration::from_std(delay).unwrap_or(self.params.tariff_refresh));
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Record the current ESS registers so [ControlLoop::shutdown] can restore them
+    pub async fn startup(&mut self) -> Result<&[ess::Register], ControlError> {
+        let prior = self.ess.get_params().await?;
+        Ok(self.prior.insert(prior))
+    }
+
+    /// Check the GX will act on our writes, switching the ESS to external
+    /// control if `switch` is set. The previous mode is restored on shutdown
+    pub async fn check(&mut self, switch: bool) -> Result<Capability, ControlError> {
+        let mut cap = self.capability().await?;
+        if !cap.honoured() && switch && cap.bus_mode == Mode::On {
+            self.ess
+                .set_param(ess::Register::Mode(Hub4Mode::External))
+                .await?;
+            self.prior_mode = Some(cap.hub4_mode);
+            cap = self.capability().await?;
+        }
+
+        if cap.honoured() {
+            Ok(cap)
+        } else {
+            Err(ControlError::NotControllable(cap))
+        }
+    }
+
+    async fn capability(&mut self) -> Result<Capability, ControlError> {
+        Ok(Capability {
+            hub4_mode: self.ess.get_mode().await?,
+            bus_mode: self.bus.get_mode().await?,
+            min_soc: self.system.ess_min_soc().await.ok(),
+        })
+    }
+
+    /// Write the configured shutdown registers and restore the ESS mode if
+    /// we switched it, every register is attempted and the last error is returned
+    pub async fn shutdown(&mut self) -> Result<Vec<ess::Register>, ControlError> {
+        let mut regs = self.shutdown.registers(self.prior.as_deref());
+        regs.extend(self.prior_mode.map(ess::Register::Mode));
+        let mut ret = Ok(regs.clone());
+        for reg in regs {
+            if let Err(e) = self.ess.set_param(reg).await {
+                ret = Err(e.into());
+            }
+        }
+        ret
+    }
+
+    pub async fn tick(&mut self, now: DateTime<Utc>) -> Result<Tick, ControlError> {
+        let bus = self.bus.snapshot().await?;
+
+        // whole-house consumption, or only the inverter output without the system service
+        let system = self.system.snapshot().await.ok();
+        let system_load = match system {
+            Some(s) => s.consumption_power(),
+            None => bus.output.iter().map(|o| o.power).sum(),
+        };
+        // grid import above the loads goes into the battery
+        let grid_power = match system {
+            Some(s) => s.grid_power(),
+            None => bus.input.iter().map(|i| i.power).sum(),
+        };
+
+        let phases = (bus.phase_count as usize).clamp(1, 3);
+        let (phase_loads, phase_compensation) = if phases > 1 {
+            let loads = match system {
+                Some(s) => s.consumption[..phases].to_vec(),
+                None => bus.output[..phases].iter().map(|o| o.power).collect(),
+            };
+            (loads, self.params.phase_compensation)
+        } else {
+            (vec![], false)
+        };
+
+        // fall back to the VE.Bus readings and configured capacity without a battery monitor
+        let battery = match self.battery.state().await {
+            Ok(b) if b.capacity > 0.0 => Some(b),
+            _ => None,
+        };
+        let input = match battery {
+            Some(b) => ControllerInputState {
+                system_load,
+                soc: b.soc / 100.0,
+                capacity: b.capacity_kwh(self.params.nominal_voltage),
+                voltage: b.voltage,
+                charge_current_limit: b.charge_current_limit,
+                discharge_current_limit: b.discharge_current_limit,
+                phase_loads,
+                phase_compensation,
+                window_units: self.usage.units(now),
+            },
+            None => ControllerInputState {
+                system_load,
+                soc: bus.soc / 100.0,
+                capacity: self.params.capacity,
+                voltage: bus.battery_voltage,
+                charge_current_limit: None,
+                discharge_current_limit: None,
+                phase_loads,
+                phase_compensation,
+                window_units: self.usage.units(now),
+            },
+        };
+
+        let mut desired_state = self.controller.desired_state(now, input)?;
+        let triggered = self.interlock.apply(&bus, &mut desired_state);
+
+        // grid import above the loads, the battery can't take more than it
+        // charges at, which also bounds the estimate when PV on the output
+        // is hidden from the inverter readings without the system service
+        let battery_power = match system {
+            Some(s) => s.battery_power,
+            None => bus.battery_voltage * bus.battery_current,
+        };
+        let charge_power = (grid_power - system_load).min(battery_power);
+        let new_window = self
+            .usage
+            .record(&desired_state.current_rate, now, charge_power);
+        let save_due = new_window
+            || !matches!(self.usage_saved, Some(t) if now - t < usage_save_interval());
+        let mut usage_error = None;
+        if let (Some(path), true) = (&self.usage_path, save_due) {
+            match self.usage.save(path) {
+                Ok(_) => self.usage_saved = Some(now),
+                Err(e) => usage_error = Some(e.to_string()),
+            }
+        }
+
+        // drifted registers are dropped from the shadow and written again below
+        let drift = match self.verified {
+            Some(t) if now - t < self.params.verify_interval => vec![],
+            _ => {
+                let drift = self.ess.verify().await?;
+                self.verified = Some(now);
+                drift
+            }
+        };
+
+        let max_grid_load = triggered.iter().filter_map(|r| r.max_grid_load).reduce(f32::min);
+        let set_points = self.set_points(&desired_state, max_grid_load);
+        for (line, sp) in [Line::L1, Line::L2, Line::L3].into_iter().zip(&set_points) {
+            self.ess
+                .update_param(ess::Register::PowerSetPoint(line, *sp), now)
+                .await?;
+        }
+
+        self.ess
+            .update_param(ess::Register::DisableFeedIn(desired_state.disable_feed_in), now)
+            .await?;
+
+        self.ess
+            .update_param(ess::Register::DisableCharge(desired_state.disable_charge), now)
+            .await?;
+
+        Ok(Tick {
+            set_points,
+            drift,
+            state: desired_state,
+            triggered,
+            battery,
+            system,
+            usage_error,
+        })
+    }
+
+    /// Grid setpoint per phase, the minimum applies to each phase or with
+    /// phase compensation to their total unless the controller is exporting.
+    /// An interlock `max_grid_load` is applied last and split equally over
+    /// the phases, so it also caps the minimum
+    fn set_points(&self, state: &ControllerOutputState, max_grid_load: Option<f32>) -> Vec<i16> {
+        let loads = state.grid_loads();
+        let min = if state.grid_load < 0.0 {
+            f32::MIN
+        } else {
+            self.params.min_set_point as f32
+        };
+        let deficit = (min - loads.iter().sum::<f32>()).max(0.0) / loads.len() as f32;
+        let cap = max_grid_load.map_or(f32::MAX, |m| m / loads.len() as f32);
+        loads
+            .iter()
+            .map(|l| {
+                let sp = if state.phase_compensation {
+                    l + deficit
+                } else {
+                    l.max(min)
+                };
+                sp.min(cap) as i16
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::safety::Trigger;
+    use crate::control::shutdown::Shutdown;
+    use crate::victron::sim::{Simulator, BATTERY, INVERTER, SYSTEM};
+    use crate::victron::ve_bus::AlarmState;
+    use chrono::{Local, TimeZone};
+
+    fn get_controller() -> Controller {
+        serde_json::from_str(
+            r#"{
+              "dod": 0.8,
+              "rates": [
+                {
+                  "name": "Day",
+                  "unit_cost": 0.25,
+                  "windows": [{"days": ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"],
+                               "start": {"hour": 9, "minute": 0}, "end": {"hour": 22, "minute": 59}}],
+                  "discharge": {"mode": {"Capacity": 1.0}, "max_power": 2500.0},
+                  "charge": {"mode": "Disabled", "unit_limit": 0},
+                  "reserve": 0
+                },
+                {
+                  "name": "Night",
+                  "unit_cost": 0.18,
+                  "windows": [{"days": ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"],
+                               "start": {"hour": 23, "minute": 0}, "end": {"hour": 8, "minute": 59}}],
+                  "discharge": {"mode": "None", "max_power": 0.0},
+                  "charge": {"mode": {"Capacity": 1.0}, "unit_limit": 0},
+                  "reserve": 0
+                }
+              ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tick_discharge() {
+        let sim = Simulator::start().await;
+        sim.set_output_power(Line::L1, 1500.0);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+
+        assert_eq!(tick.state.battery_load, 1500.0);
+        assert!(tick.triggered.is_empty());
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(50), "min setpoint");
+        assert_eq!(sim.get(INVERTER, 38), Some(1), "charge disabled");
+        assert_eq!(sim.get(INVERTER, 39), Some(0), "feed-in enabled");
+    }
+
+    #[tokio::test]
+    async fn tick_params() {
+        let sim = Simulator::start().await;
+        sim.set_output_power(Line::L1, 1500.0);
+        sim.set(BATTERY, 309, 0); // capacity not configured
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+        ctl.set_params(Params {
+            capacity: 10.0,
+            min_set_point: 20,
+            ..Default::default()
+        });
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+
+        assert!(tick.battery.is_none());
+        assert_eq!(tick.state.using_capacity, 10.0 * (0.8 - (1.0 - 0.8)));
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(20), "min setpoint");
+    }
+
+    #[tokio::test]
+    async fn tick_battery() {
+        let sim = Simulator::start().await;
+        sim.set(BATTERY, 266, 500);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+
+        assert_eq!(tick.battery.unwrap().capacity_kwh(48.0), 6.72);
+        assert_eq!(tick.state.soc, 0.5, "battery monitor soc");
+        assert_eq!(tick.state.using_capacity, 6.72 * (0.5 - (1.0 - 0.8)));
+    }
+
+    #[tokio::test]
+    async fn tick_system_load() {
+        let sim = Simulator::start().await;
+        sim.set_output_power(Line::L1, 1000.0);
+        sim.set(SYSTEM, 818, 800); // load on the input side
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+        assert_eq!(tick.state.battery_load, 1800.0);
+        assert_eq!(tick.system.unwrap().consumption_power(), 1800.0);
+
+        // fall back to the inverter output without the system service
+        sim.remove(SYSTEM, 817);
+        let tick = ctl.tick(now).await.unwrap();
+        assert!(tick.system.is_none());
+        assert_eq!(tick.state.battery_load, 1000.0);
+    }
+
+    #[tokio::test]
+    async fn tick_three_phase() {
+        let sim = Simulator::start().await;
+        sim.set(INVERTER, 28, 3);
+        sim.set_output_power(Line::L1, 1200.0);
+        sim.set_output_power(Line::L2, 600.0);
+        sim.set_output_power(Line::L3, 0.0);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+        sim.set(BATTERY, 308, 100); // 10A DCL leaves some load on the grid
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+        assert_eq!(tick.state.battery_load, 520.0);
+        assert_eq!(tick.set_points, vec![853, 426, 50]);
+        assert_eq!(sim.get_i16(INVERTER, 40), Some(426));
+        assert_eq!(sim.get_i16(INVERTER, 41), Some(50));
+
+        // shared equally, L3 may feed in while the total stays above the minimum
+        ctl.set_params(Params {
+            phase_compensation: true,
+            ..Params::default()
+        });
+        let tick = ctl.tick(now).await.unwrap();
+        assert_eq!(tick.set_points, vec![1026, 426, -173]);
+        assert_eq!(sim.get_i16(INVERTER, 41), Some(-173));
+    }
+
+    #[tokio::test]
+    async fn tick_refresh() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        // constant load, so the setpoint doesn't change between ticks
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        for s in [0, 10, 20] {
+            ctl.tick(now + Duration::try_seconds(s).unwrap()).await.unwrap();
+        }
+        assert_eq!(sim.writes(INVERTER, 37), 1, "unchanged setpoint skipped");
+
+        ctl.tick(now + Duration::try_seconds(30).unwrap()).await.unwrap();
+        assert_eq!(sim.writes(INVERTER, 37), 2, "rewritten after the verify interval");
+        assert_eq!(sim.writes(INVERTER, 38), 2);
+        assert_eq!(sim.writes(INVERTER, 39), 2);
+    }
+
+    #[tokio::test]
+    async fn tick_drift() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        ctl.tick(now).await.unwrap();
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(50));
+
+        // changed in VRM, not noticed until the next read-back
+        sim.set(INVERTER, 37, 1000);
+        let tick = ctl.tick(now + Duration::try_seconds(10).unwrap()).await.unwrap();
+        assert!(tick.drift.is_empty());
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(1000), "no redundant write");
+
+        let tick = ctl.tick(now + Duration::try_minutes(1).unwrap()).await.unwrap();
+        assert_eq!(tick.drift.len(), 1);
+        assert_eq!(tick.drift[0].actual, 1000);
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(50), "re-asserted");
+    }
+
+    #[tokio::test]
+    async fn tick_charge() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        ctl.tick(now).await.unwrap();
+
+        // 500W load plus 70A CCL at 52V
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(4_140));
+        assert_eq!(sim.get(INVERTER, 38), Some(0), "charge enabled");
+        assert_eq!(sim.get(INVERTER, 39), Some(1), "feed-in disabled");
+        assert_eq!(sim.get_i16(INVERTER, 12), Some(414), "grid follows setpoint");
+    }
+
+    #[tokio::test]
+    async fn tick_unit_limit() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let json = serde_json::to_string(&get_controller())
+            .unwrap()
+            .replace(r#""unit_limit":0"#, r#""unit_limit":1"#);
+        let ctr: Controller = serde_json::from_str(&json).unwrap();
+
+        // 1kWh already charged in this window before a restart
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let sch = ctr.get_schedule(now).remove(0);
+        let mut usage = UnitUsage::default();
+        usage.record(&sch, now - Duration::try_minutes(5).unwrap(), 0.0);
+        usage.record(&sch, now, 12_000.0);
+        let path = std::env::temp_dir().join(format!("ve_smart_ess_tick_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        usage.save(path).unwrap();
+
+        let mut ctl = ControlLoop::new(ctr, &conn, Units::default());
+        ctl.set_usage_path(path).unwrap();
+        let tick = ctl.tick(now).await.unwrap();
+        let saved = UnitUsage::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(tick.state.window_units, 1.0);
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(500), "limit reached, grid covers the load");
+        assert_eq!(saved.units(now), 1.0);
+    }
+
+    #[tokio::test]
+    async fn tick_usage_save() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+        let path = std::env::temp_dir().join(format!("ve_smart_ess_save_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // corrupt usage is reported and replaced on the first save
+        std::fs::write(path, "not json").unwrap();
+        assert!(ctl.set_usage_path(path).is_err());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        ctl.tick(now).await.unwrap();
+        assert!(UnitUsage::load(path).is_ok());
+        std::fs::remove_file(path).expect("saved on the first tick");
+
+        ctl.tick(now + Duration::try_seconds(10).unwrap()).await.unwrap();
+        assert!(std::fs::metadata(path).is_err(), "not saved on every tick");
+        ctl.tick(now + Duration::try_minutes(1).unwrap()).await.unwrap();
+        std::fs::remove_file(path).expect("saved after the interval");
+
+        // a failed save is reported but doesn't stop the ESS writes
+        ctl.set_usage_path("/nonexistent/ve_smart_ess_usage.json").unwrap();
+        sim.set(INVERTER, 37, 0);
+        let tick = ctl.tick(now + Duration::try_minutes(3).unwrap()).await.unwrap();
+        assert!(tick.usage_error.is_some());
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(4_140));
+    }
+
+    #[tokio::test]
+    async fn refresh_prices() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let path = std::env::temp_dir().join(format!("ve_smart_ess_prices_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "2022-05-03T00:00:00Z,2022-05-03T00:30:00Z,0.05\n").unwrap();
+
+        let mut json = serde_json::to_value(get_controller()).unwrap();
+        json["tariff"] = serde_json::json!({
+            "source": {"File": path},
+            "format": "Csv",
+            "charge_below": 0.1,
+            "discharge_above": 0.3,
+            "charge": {"mode": {"Capacity": 1.0}, "unit_limit": 0},
+            "discharge": {"mode": "Spread", "max_power": 2500.0},
+            "normal": {"mode": "None", "max_power": 0.0}
+        });
+        let mut ctl = ControlLoop::new(serde_json::from_value(json).unwrap(), &conn, Units::default());
+
+        let now = Utc.with_ymd_and_hms(2022, 5, 3, 0, 0, 0).unwrap();
+        let first = ctl.refresh_prices(now).await;
+        let second = ctl.refresh_prices(now + Duration::try_minutes(10).unwrap()).await;
+        std::fs::remove_file(path).unwrap();
+        let failed = now + Duration::try_minutes(30).unwrap();
+        let missing = ctl.refresh_prices(failed).await;
+
+        assert_eq!(first.unwrap(), Some(1));
+        assert_eq!(second.unwrap(), None, "not due");
+        assert!(matches!(missing, Err(ControlError::Controller(ControllerError::Tariff(_)))));
+
+        // retried after a minute, then after two
+        let at = |s| failed + Duration::try_seconds(s).unwrap();
+        assert_eq!(ctl.refresh_prices(at(10)).await.unwrap(), None, "backing off");
+        assert!(ctl.refresh_prices(at(60)).await.is_err());
+        assert_eq!(ctl.refresh_prices(at(120)).await.unwrap(), None, "backing off");
+        std::fs::write(path, "2022-05-03T00:00:00Z,2022-05-03T00:30:00Z,0.05\n").unwrap();
+        let back = ctl.refresh_prices(at(180)).await;
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(back.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn tick_export() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut json = serde_json::to_value(get_controller()).unwrap();
+        json["export_limit"] = serde_json::json!(1500.0);
+        json["rates"][0]["export_price"] = serde_json::json!(0.3);
+        json["rates"][0]["discharge"] = serde_json::json!({"mode": "Export", "max_power": 5000.0});
+        let mut ctl = ControlLoop::new(serde_json::from_value(json).unwrap(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+
+        // 500W load plus the export limit, below the minimum setpoint
+        assert_eq!(tick.state.battery_load, 2000.0);
+        assert_eq!(tick.set_points, vec![-1500]);
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(-1500));
+        assert_eq!(sim.get(INVERTER, 39), Some(0), "feed-in enabled");
+    }
+
+    #[tokio::test]
+    async fn interlock_alarm() {
+        let sim = Simulator::start().await;
+        sim.set_output_power(Line::L1, 1500.0);
+        sim.set(INVERTER, 44, 1); // L1 high temperature warning
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+
+        assert_eq!(tick.triggered.len(), 1);
+        assert_eq!(tick.triggered[0].trigger, Trigger::HighTemperature);
+        assert!(tick.state.disable_feed_in);
+        assert_eq!(tick.state.battery_load, 0.0);
+        assert_eq!(tick.state.grid_load, 1500.0);
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(1500), "grid covers the load");
+        assert_eq!(sim.get(INVERTER, 39), Some(1), "feed-in disabled");
+    }
+
+    #[tokio::test]
+    async fn interlock_fault() {
+        let sim = Simulator::start().await;
+        sim.set(INVERTER, 31, 2); // fault
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+        ctl.set_interlock(Interlock {
+            rules: vec![SafetyRule {
+                trigger: Trigger::Fault,
+                level: AlarmState::Alarm,
+                disable_feed_in: true,
+                disable_charge: true,
+                max_grid_load: Some(0.0),
+            }],
+        });
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let tick = ctl.tick(now).await.unwrap();
+
+        assert_eq!(tick.triggered.len(), 1);
+        assert!(tick.state.disable_charge);
+        assert_eq!(sim.get(INVERTER, 38), Some(1), "charge disabled");
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(0), "setpoint capped below the minimum");
+    }
+
+    #[test]
+    fn interlock_config() {
+        let i: Interlock = serde_json::from_str(
+            r#"{"rules": [{"trigger": "Overload", "level": "Alarm", "max_grid_load": 3000.0}]}"#,
+        )
+        .unwrap();
+        assert_eq!(i.rules[0].trigger, Trigger::Overload);
+        assert_eq!(i.rules[0].level, AlarmState::Alarm);
+        assert!(!i.rules[0].disable_feed_in);
+        assert_eq!(i.rules[0].max_grid_load, Some(3000.0));
+    }
+
+    #[tokio::test]
+    async fn check_mode() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+        ctl.set_shutdown(Shutdown::Restore);
+        ctl.startup().await.unwrap();
+
+        let err = ctl.check(false).await.unwrap_err();
+        assert!(matches!(err, ControlError::NotControllable(ref c) if c.hub4_mode == Hub4Mode::WithPhaseCompensation));
+        assert_eq!(sim.get(SYSTEM, 2902), Some(1), "not switched");
+
+        let cap = ctl.check(true).await.unwrap();
+        assert!(cap.honoured());
+        assert_eq!(cap.min_soc, Some(10.0));
+        assert_eq!(sim.get(SYSTEM, 2902), Some(3));
+
+        ctl.shutdown().await.unwrap();
+        assert_eq!(sim.get(SYSTEM, 2902), Some(1), "mode restored");
+    }
+
+    #[tokio::test]
+    async fn check_inverter_off() {
+        let sim = Simulator::start().await;
+        sim.set(INVERTER, 33, 4); // off
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        assert!(matches!(ctl.check(true).await, Err(ControlError::NotControllable(_))));
+        assert_eq!(sim.get(SYSTEM, 2902), Some(1), "not switched while off");
+    }
+
+    #[tokio::test]
+    async fn check_without_startup() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        // no prior registers, the mode is still restored
+        ctl.check(true).await.unwrap();
+        assert_eq!(sim.get(SYSTEM, 2902), Some(3));
+        ctl.shutdown().await.unwrap();
+        assert_eq!(sim.get(SYSTEM, 2902), Some(1), "mode restored");
+    }
+
+    #[tokio::test]
+    async fn shutdown_restore() {
+        let sim = Simulator::start().await;
+        sim.set(INVERTER, 37, -300i16 as u16);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+        ctl.set_shutdown(Shutdown::Restore);
+        ctl.startup().await.unwrap();
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        ctl.tick(now).await.unwrap();
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(4_140));
+
+        ctl.shutdown().await.unwrap();
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(-300));
+        assert_eq!(sim.get(INVERTER, 39), Some(0), "feed-in enabled");
+    }
+
+    #[tokio::test]
+    async fn shutdown_safe() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ctl = ControlLoop::new(get_controller(), &conn, Units::default());
+
+        let now = Local.with_ymd_and_hms(2022, 5, 3, 12, 0, 0).unwrap().with_timezone(&Utc);
+        ctl.tick(now).await.unwrap();
+        assert_eq!(sim.get(INVERTER, 38), Some(1), "charge disabled");
+
+        let written = ctl.shutdown().await.unwrap();
+        assert_eq!(written, Shutdown::safe());
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(0));
+        assert_eq!(sim.get(INVERTER, 38), Some(0), "charge enabled");
+        assert_eq!(sim.get(INVERTER, 39), Some(0), "feed-in enabled");
+    }
+
+    #[test]
+    fn shutdown_config() {
+        let s: Shutdown = serde_json::from_str(
+            r#"{"Write": [{"PowerSetPoint": ["L1", 100]}, {"DisableCharge": false}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            s.registers(None),
+            vec![ess::Register::PowerSetPoint(Line::L1, 100), ess::Register::DisableCharge(false)]
+        );
+        assert_eq!(Shutdown::Restore.registers(None), Shutdown::safe());
+    }
+
+    #[test]
+    fn sample_config() {
+        let cfg = Config::load("smart_ess.json").unwrap();
+        assert_eq!(cfg.safety, Interlock::default());
+        assert_eq!(cfg.shutdown, Shutdown::Restore);
+        assert!(Controller::load("smart_ess.json").is_ok());
+    }
+}
@@ -0,0 +1,146 @@
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+
+use crate::smart_ess::ControllerOutputState;
+use crate::victron::ve_bus::{ActiveInput, AlarmSnapshot, AlarmState, BusSnapshot, LineAlarms, State};
+
+/// Condition reported by the inverter which can trigger a [SafetyRule]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    /// High temperature alarm, on the inverter or any line
+    HighTemperature,
+
+    /// Low battery alarm, on the inverter or any line
+    LowBattery,
+
+    /// Overload alarm, on the inverter or any line
+    Overload,
+
+    /// Grid lost alarm
+    GridLost,
+
+    /// No AC input is connected
+    NoActiveInput,
+
+    /// Inverter is in the fault state
+    Fault,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SafetyRule {
+    pub trigger: Trigger,
+
+    /// Lowest alarm state which triggers this rule, state triggers count as [AlarmState::Alarm]
+    #[serde(default = "default_level")]
+    pub level: AlarmState,
+
+    /// Stop discharging the battery into loads or the grid
+    #[serde(default)]
+    pub disable_feed_in: bool,
+
+    /// Stop charging the battery
+    #[serde(default)]
+    pub disable_charge: bool,
+
+    /// Upper limit for the grid setpoint in watts
+    #[serde(default)]
+    pub max_grid_load: Option<f32>,
+}
+
+/// Rules forcing conservative ESS outputs when the inverter reports problems
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Interlock {
+    pub rules: Vec<SafetyRule>,
+}
+
+fn default_level() -> AlarmState {
+    AlarmState::Warning
+}
+
+impl Default for Interlock {
+    fn default() -> Self {
+        let rule = |trigger, level, disable_feed_in, disable_charge| SafetyRule {
+            trigger,
+            level,
+            disable_feed_in,
+            disable_charge,
+            max_grid_load: None,
+        };
+        Self {
+            rules: vec![
+                rule(Trigger::HighTemperature, AlarmState::Warning, true, true),
+                rule(Trigger::Overload, AlarmState::Warning, true, false),
+                rule(Trigger::LowBattery, AlarmState::Warning, true, false),
+                rule(Trigger::GridLost, AlarmState::Alarm, true, true),
+                rule(Trigger::Fault, AlarmState::Alarm, true, true),
+            ],
+        }
+    }
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            Trigger::HighTemperature => "High Temperature",
+            Trigger::LowBattery => "Low Battery",
+            Trigger::Overload => "Overload",
+            Trigger::GridLost => "Grid Lost",
+            Trigger::NoActiveInput => "No Active Input",
+            Trigger::Fault => "Fault",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl Trigger {
+    /// Worst alarm state for this trigger, state triggers map to [AlarmState::Alarm]
+    fn state(&self, bus: &BusSnapshot) -> AlarmState {
+        let a: &AlarmSnapshot = &bus.alarms;
+        let lines = |f: fn(&LineAlarms) -> AlarmState| {
+            a.lines.iter().map(f).max().unwrap_or(AlarmState::Ok)
+        };
+        let active = |v: bool| if v { AlarmState::Alarm } else { AlarmState::Ok };
+        match self {
+            Trigger::HighTemperature => a
+                .high_temperature
+                .state
+                .max(lines(|l| l.temperature.state)),
+            Trigger::LowBattery => a.low_battery.state.max(lines(|l| l.low_battery.state)),
+            Trigger::Overload => a.overload.state.max(lines(|l| l.overload.state)),
+            Trigger::GridLost => a.grid_lost.state,
+            Trigger::NoActiveInput => active(matches!(bus.active_input, ActiveInput::Disconnected)),
+            Trigger::Fault => active(bus.state == State::Fault),
+        }
+    }
+}
+
+impl Interlock {
+    /// Apply all triggered rules to `state`, returning the rules which fired
+    pub fn apply(&self, bus: &BusSnapshot, state: &mut ControllerOutputState) -> Vec<SafetyRule> {
+        let triggered: Vec<SafetyRule> = self
+            .rules
+            .iter()
+            .filter(|r| {
+                let s = r.trigger.state(bus);
+                s != AlarmState::Ok && s >= r.level
+            })
+            .copied()
+            .collect();
+
+        for rule in &triggered {
+            if rule.disable_feed_in {
+                state.disable_feed_in = true;
+                state.grid_load += state.battery_load;
+                state.battery_load = 0.0;
+            }
+            if rule.disable_charge {
+                state.disable_charge = true;
+            }
+            if let Some(max) = rule.max_grid_load {
+                state.grid_load = state.grid_load.min(max);
+            }
+        }
+        triggered
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::victron::ess::Register;
+use crate::victron::Line;
+
+/// ESS registers written when the process stops
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Shutdown {
+    /// Write back the values read at startup, falls back to [Shutdown::safe]
+    /// if they could not be read
+    Restore,
+
+    /// Write a fixed set of registers
+    Write(Vec<Register>),
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::Write(Shutdown::safe())
+    }
+}
+
+impl Shutdown {
+    /// Plain ESS operation, charger and feed-in enabled with a zero grid setpoint
+    pub fn safe() -> Vec<Register> {
+        vec![
+            Register::PowerSetPoint(Line::L1, 0),
+            Register::PowerSetPoint(Line::L2, 0),
+            Register::PowerSetPoint(Line::L3, 0),
+            Register::DisableCharge(false),
+            Register::DisableFeedIn(false),
+        ]
+    }
+
+    /// Registers to write given the values recorded at startup
+    pub fn registers(&self, prior: Option<&[Register]>) -> Vec<Register> {
+        match (self, prior) {
+            (Shutdown::Restore, Some(p)) => p.to_vec(),
+            (Shutdown::Restore, None) => Shutdown::safe(),
+            (Shutdown::Write(r), _) => r.clone(),
+        }
+    }
+}
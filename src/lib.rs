@@ -0,0 +1,3 @@
+pub mod control;
+pub mod smart_ess;
+pub mod victron;
@@ -4,56 +4,198 @@ use std::net::SocketAddr;
 use std::time::Duration;
 
 use chrono::{Local, Utc};
+use clap::Parser;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::smart_ess::{Controller, ControllerInputState};
-use crate::victron::{ess, Line, Side, VictronError};
-use crate::victron::ess::VictronESS;
-use crate::victron::ve_bus::VictronBus;
+use ve_smart_ess::control::{Config, ControlError, ControlLoop, Params, Units};
+use ve_smart_ess::smart_ess::Controller;
+use ve_smart_ess::victron::client::{Backoff, ConnectionState, Transport, VictronConnection};
 
-mod smart_ess;
-mod victron;
+/// Victron ESS controller driven by time of use rates
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Modbus TCP address of the GX device
+    #[arg(long, env = "VE_GX_ADDR", default_value = "10.100.2.17:502")]
+    gx: SocketAddr,
 
-const INVERTER: u8 = 227;
-//const BATTERY: u8 = 225;
-//const SYSTEM: u8 = 100;
+    /// Serial device for Modbus RTU, used instead of the GX address
+    #[cfg(feature = "rtu")]
+    #[arg(long, env = "VE_SERIAL")]
+    serial: Option<String>,
+
+    /// Serial line baud rate
+    #[cfg(feature = "rtu")]
+    #[arg(long, env = "VE_BAUD_RATE", default_value_t = 19200)]
+    baud_rate: u32,
+
+    /// VE.Bus inverter unit id
+    #[arg(long, env = "VE_INVERTER_UNIT", default_value_t = 227)]
+    inverter: u8,
+
+    /// Battery monitor unit id
+    #[arg(long, env = "VE_BATTERY_UNIT", default_value_t = 225)]
+    battery: u8,
+
+    /// System unit id
+    #[arg(long, env = "VE_SYSTEM_UNIT", default_value_t = 100)]
+    system: u8,
+
+    /// Rate and safety config file
+    #[arg(long, env = "VE_CONFIG", default_value = "smart_ess.json")]
+    config: String,
+
+    /// File recording grid energy used for charging in the current rate window
+    #[arg(long, env = "VE_USAGE", default_value = "smart_ess_usage.json")]
+    usage: String,
+
+    /// Seconds between control loop ticks
+    #[arg(long, env = "VE_INTERVAL", default_value_t = 10)]
+    interval: u64,
+
+    /// Battery capacity in kWh, used when the battery monitor does not report one
+    #[arg(long, env = "VE_CAPACITY", default_value_t = 7.2)]
+    capacity: f32,
+
+    /// Nominal battery voltage, converts the battery monitor capacity in Ah to kWh
+    #[arg(long, env = "VE_NOMINAL_VOLTAGE", default_value_t = 48.0)]
+    nominal_voltage: f32,
+
+    /// Seconds to wait for the GX to connect or answer a request
+    #[arg(long, env = "VE_TIMEOUT", default_value_t = 5)]
+    timeout: u64,
+
+    /// Seconds between read-backs of the ESS registers, unchanged registers
+    /// are also rewritten at this interval and it must stay below 60
+    #[arg(
+        long,
+        env = "VE_VERIFY_INTERVAL",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(i64).range(1..60)
+    )]
+    verify_interval: i64,
+
+    /// Minutes between dynamic tariff price fetches, at most once a day
+    #[arg(
+        long,
+        env = "VE_TARIFF_REFRESH",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(i64).range(1..=1440)
+    )]
+    tariff_refresh: i64,
+
+    /// Balance the grid setpoint over all phases instead of per phase
+    #[arg(long, env = "VE_PHASE_COMPENSATION")]
+    phase_compensation: bool,
+
+    /// Switch the ESS to external control if it is in another mode
+    #[arg(long, env = "VE_SWITCH_MODE")]
+    switch_mode: bool,
+
+    /// Lowest grid setpoint in watts
+    #[arg(long, env = "VE_MIN_SET_POINT", default_value_t = 50, allow_negative_numbers = true)]
+    min_set_point: i16,
+}
+
+impl Args {
+    fn transport(&self) -> Transport {
+        #[cfg(feature = "rtu")]
+        if let Some(path) = &self.serial {
+            return Transport::Rtu {
+                path: path.clone(),
+                baud_rate: self.baud_rate,
+            };
+        }
+        Transport::Tcp(self.gx)
+    }
+}
 
 #[tokio::main]
-pub async fn main() -> Result<(), VictronError> {
-    let addr: SocketAddr = "10.100.2.17:502".parse().unwrap();
-    let mut vs = VictronBus::new(addr, INVERTER).await?;
-    let mut ess = VictronESS::new(addr, INVERTER).await?;
+pub async fn main() -> Result<(), ControlError> {
+    let args = Args::parse();
 
-    let ctr = Controller::load().map_err(|e| VictronError(e.0))?;
+    let mut term = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let transport = args.transport();
+    println!("Connecting to {}", transport);
+    let timeout = Duration::from_secs(args.timeout);
+    let backoff = Backoff::default();
+    let mut attempt = 0;
+    let conn = loop {
+        match VictronConnection::open_with_timeout(transport.clone(), timeout).await {
+            Ok(conn) => break conn,
+            Err(e) => {
+                attempt += 1;
+                let delay = backoff.delay_with_jitter(attempt);
+                println!("Failed to connect: {}, retrying in {:?}", e, delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = term.recv() => return Ok(()),
+                    _ = tokio::signal::ctrl_c() => return Ok(()),
+                }
+            }
+        }
+    };
 
-    loop {
-        let soc = vs.soc().await?;
-        let out1 = vs.get_line_info(Side::Output, Line::L1).await?;
+    let units = Units {
+        inverter: args.inverter,
+        battery: args.battery,
+        system: args.system,
+    };
+    let ctr = Controller::load(&args.config)?;
+    let mut ctl = ControlLoop::new(ctr, &conn, units);
+    ctl.set_config(Config::load(&args.config)?);
+    if let Err(e) = ctl.set_usage_path(&args.usage) {
+        println!("Warning: starting with empty charge usage: {}", e);
+    }
+    ctl.set_params(Params {
+        capacity: args.capacity,
+        nominal_voltage: args.nominal_voltage,
+        phase_compensation: args.phase_compensation,
+        min_set_point: args.min_set_point,
+        verify_interval: chrono::Duration::try_seconds(args.verify_interval)
+            .expect("Verify interval is range checked by clap"),
+        tariff_refresh: chrono::Duration::try_minutes(args.tariff_refresh)
+            .expect("Tariff refresh is range checked by clap"),
+    });
 
+    match ctl.startup().await {
+        Ok(prior) => println!("Prior ESS state: {:?}", prior),
+        Err(e) => println!("Failed to read prior ESS state: {}", e),
+    }
+    let cap = ctl.check(args.switch_mode).await?;
+    println!("{}", cap);
+
+    loop {
         println!("====================");
         println!("Time: {}", Local::now());
 
-        let desired_state = ctr
-            .desired_state(
-                Utc::now(),
-                ControllerInputState {
-                    system_load: out1.power,
-                    soc: soc / 100.0,
-                    capacity: 7.2,
-                    voltage: 0.0,
-                },
-            ).unwrap();
-        println!("{}", desired_state);
-
-        let target_set_point = (desired_state.grid_load as i16).max(50);
-        ess.set_param(ess::Register::PowerSetPoint(Line::L1, target_set_point))
-            .await?;
-
-        ess.set_param(ess::Register::DisableFeedIn(desired_state.disable_feed_in))
-            .await?;
-
-        ess.set_param(ess::Register::DisableCharge(desired_state.disable_charge))
-            .await?;
-
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        match ctl.refresh_prices(Utc::now()).await {
+            Ok(Some(n)) => println!("Loaded {} price slots", n),
+            Ok(None) => {}
+            Err(e) => println!("Failed to refresh prices: {}", e),
+        }
+
+        match ctl.tick(Utc::now()).await {
+            Ok(tick) => println!("{}", tick),
+            Err(e) => {
+                println!("Error: {}", e);
+                if let ConnectionState::Reconnecting { attempt } = conn.state().await {
+                    println!("Connection lost, reconnect attempt {}", attempt);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(args.interval)) => {}
+            _ = term.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    println!("Shutting down, restoring ESS state");
+    match ctl.shutdown().await {
+        Ok(regs) => println!("Wrote {:?}", regs),
+        Err(e) => println!("Failed to restore ESS state: {}", e),
     }
+    Ok(())
 }
@@ -5,18 +5,82 @@ use std::io::Read;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use crate::smart_ess::rate::{DischargeMode, Rate, RateDischarge};
+use crate::smart_ess::rate::{ChargeMode, ChargeStrategy, DischargeMode, Rate};
+use crate::smart_ess::planner::{Plan, PlanInput, Planner, PlannerConfig};
+use crate::smart_ess::tariff::{DynamicTariff, PriceSlot, TariffError};
 use crate::smart_ess::window::RateWindowAbsolute;
 
+pub mod planner;
 pub mod rate;
+pub mod tariff;
+pub mod usage;
 pub mod window;
 
 #[derive(Debug)]
-pub struct ControllerError(pub String);
+pub enum ControllerError {
+    /// Config file could not be read
+    Io(std::io::Error),
 
-impl<TStr: ToString> From<TStr> for ControllerError {
-    fn from(t: TStr) -> Self {
-        ControllerError(t.to_string())
+    /// Config file is not valid
+    Config(serde_json::Error),
+
+    /// No rate covers the current time
+    NoCurrentRate,
+
+    /// No rate follows the current one
+    NoNextRate,
+
+    /// None of the rates have charging enabled
+    NoChargeRate,
+
+    /// Charge usage could not be read or saved
+    Usage(std::io::Error),
+
+    /// Dynamic tariff prices could not be loaded
+    Tariff(TariffError),
+}
+
+impl Display for ControllerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControllerError::Io(e) => write!(f, "Failed to read config: {}", e),
+            ControllerError::Config(e) => write!(f, "Invalid config: {}", e),
+            ControllerError::NoCurrentRate => write!(f, "No current rate found"),
+            ControllerError::NoNextRate => write!(f, "No next rate found"),
+            ControllerError::NoChargeRate => write!(f, "No charge rate configured"),
+            ControllerError::Usage(e) => write!(f, "Failed to access charge usage: {}", e),
+            ControllerError::Tariff(e) => write!(f, "Tariff error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ControllerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ControllerError::Io(e) => Some(e),
+            ControllerError::Config(e) => Some(e),
+            ControllerError::Usage(e) => Some(e),
+            ControllerError::Tariff(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ControllerError {
+    fn from(e: std::io::Error) -> Self {
+        ControllerError::Io(e)
+    }
+}
+
+impl From<TariffError> for ControllerError {
+    fn from(e: TariffError) -> Self {
+        ControllerError::Tariff(e)
+    }
+}
+
+impl From<serde_json::Error> for ControllerError {
+    fn from(e: serde_json::Error) -> Self {
+        ControllerError::Config(e)
     }
 }
 
@@ -27,15 +91,36 @@ pub struct Controller {
 
     /// Depth of Discharge
     dod: f32,
+
+    /// Published slot prices, used instead of the fixed rates while they last
+    #[serde(default)]
+    tariff: Option<DynamicTariff>,
+
+    /// Prices last fetched for [Controller::tariff]
+    #[serde(skip)]
+    prices: Vec<PriceSlot>,
+
+    /// Follow a cost optimised plan instead of the dispatch rules
+    #[serde(default)]
+    planner: Option<PlannerConfig>,
+
+    /// Most power in watts fed into the grid by [DischargeMode::Export], 0 disables export
+    #[serde(default)]
+    export_limit: f32,
 }
 
 #[derive(Debug, Clone)]
 pub struct Schedule {
     pub rate: Rate,
     pub window: RateWindowAbsolute,
+
+    /// Window the charge usage is counted over: the whole rate window before
+    /// it was cut around dynamic prices, or the run of back to back price
+    /// slots of the same rate
+    pub usage_window: RateWindowAbsolute,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ControllerInputState {
     /// Power usage of the system in watts
     pub system_load: f32,
@@ -48,6 +133,42 @@ pub struct ControllerInputState {
 
     /// Battery voltage
     pub voltage: f32,
+
+    /// BMS charge current limit (CCL) in amps, [None] without a BMS
+    pub charge_current_limit: Option<f32>,
+
+    /// BMS discharge current limit (DCL) in amps, [None] without a BMS
+    pub discharge_current_limit: Option<f32>,
+
+    /// Load per phase in watts, empty for a single phase system
+    pub phase_loads: Vec<f32>,
+
+    /// The ESS balances the grid over all phases, so one phase may feed in
+    /// while another imports
+    pub phase_compensation: bool,
+
+    /// Grid energy used for charging in the current rate window in kWh
+    pub window_units: f32,
+}
+
+impl ControllerInputState {
+    /// Charge power allowed by the BMS in watts
+    pub fn max_charge_power(&self) -> Option<f32> {
+        self.limit_power(self.charge_current_limit)
+    }
+
+    /// Discharge power allowed by the BMS in watts
+    pub fn max_discharge_power(&self) -> Option<f32> {
+        self.limit_power(self.discharge_current_limit)
+    }
+
+    fn limit_power(&self, current: Option<f32>) -> Option<f32> {
+        if self.voltage > 0.0 {
+            current.map(|i| (i * self.voltage).max(0.0))
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -73,6 +194,45 @@ pub struct ControllerOutputState {
     pub next_rate: Schedule,
 
     pub next_charge: Schedule,
+
+    /// Load per phase in watts, see [ControllerInputState::phase_loads]
+    pub phase_loads: Vec<f32>,
+
+    /// See [ControllerInputState::phase_compensation]
+    pub phase_compensation: bool,
+
+    /// See [ControllerInputState::window_units]
+    pub window_units: f32,
+
+    /// Plan the state was taken from, [None] without a planner
+    pub plan: Option<Plan>,
+}
+
+impl ControllerOutputState {
+    /// Split [ControllerOutputState::grid_load] over the phases, the battery
+    /// is shared equally with phase compensation and in proportion to the
+    /// phase loads without so no single phase feeds in
+    pub fn grid_loads(&self) -> Vec<f32> {
+        let n = self.phase_loads.len();
+        if n <= 1 {
+            return vec![self.grid_load];
+        }
+
+        let total: f32 = self.phase_loads.iter().sum();
+        // charging or clamping by safety rules, spread evenly
+        let extra = (self.grid_load - (total - self.battery_load)) / n as f32;
+        self.phase_loads
+            .iter()
+            .map(|load| {
+                let battery = if self.phase_compensation || total <= 0.0 {
+                    self.battery_load / n as f32
+                } else {
+                    self.battery_load * load / total
+                };
+                load - battery + extra
+            })
+            .collect()
+    }
 }
 
 impl Display for ControllerOutputState {
@@ -94,8 +254,7 @@ impl Display for ControllerOutputState {
 }
 
 impl Controller {
-    pub fn load() -> Result<Controller, ControllerError> {
-        let path = "smart_ess.json";
+    pub fn load(path: &str) -> Result<Controller, ControllerError> {
         let mut file = match File::open(path) {
             Ok(f) => f,
             Err(_) => File::create(path)?,
@@ -110,35 +269,90 @@ impl Controller {
         if let Some(v) = self
             .get_schedule(from)
             .iter()
-            .filter(|s| s.rate.charge.charge_enabled())
-            .next()
+            .find(|s| s.rate.charge.charge_enabled())
         {
             Ok(v.clone())
         } else {
-            Err(ControllerError("No rate found!".to_owned()))
+            Err(ControllerError::NoChargeRate)
         }
     }
 
+    pub fn tariff(&self) -> Option<&DynamicTariff> {
+        self.tariff.as_ref()
+    }
+
+    pub fn set_prices(&mut self, prices: Vec<PriceSlot>) {
+        self.prices = prices;
+    }
+
+    /// Fetch prices for the dynamic tariff, returning the number of slots
+    pub async fn update_prices(&mut self) -> Result<usize, ControllerError> {
+        if let Some(t) = &self.tariff {
+            self.prices = t.fetch().await?;
+        }
+        Ok(self.prices.len())
+    }
+
+    /// Upcoming rate windows, dynamic tariff slots replace the fixed rates
+    /// while they are published and fixed windows are cut around them
     pub fn get_schedule(&self, from: DateTime<Utc>) -> Vec<Schedule> {
+        let dynamic = match &self.tariff {
+            Some(t) => t.schedule(&self.prices, from),
+            None => vec![],
+        };
+        // contiguous runs of price slots, the feed may have gaps between them
+        let mut covered: Vec<(DateTime<Utc>, DateTime<Utc>)> = vec![];
+        for s in &dynamic {
+            match covered.last_mut() {
+                Some(c) if s.window.start <= c.1 => c.1 = c.1.max(s.window.end),
+                _ => covered.push((s.window.start, s.window.end)),
+            }
+        }
+
         let mut sch: Vec<Schedule> = self
             .rates
             .iter()
             .map(|e| (e, e.schedule(from)))
-            .map(|e| {
+            .flat_map(|e| {
                 e.1.iter()
                     .map(|f| Schedule {
                         rate: e.0.clone(),
                         window: f.clone(),
+                        usage_window: f.clone(),
                     })
                     .collect::<Vec<Schedule>>()
             })
-            .flatten()
+            // fixed windows only fill the time around the runs of dynamic prices
+            .flat_map(|s| {
+                let mut parts = vec![s];
+                for (start, end) in &covered {
+                    parts = parts
+                        .into_iter()
+                        .flat_map(|p| {
+                            let mut before = p.clone();
+                            before.window.end = before.window.end.min(*start);
+                            let mut after = p;
+                            after.window.start = after.window.start.max(*end);
+                            [before, after]
+                        })
+                        .filter(|p| p.window.start < p.window.end)
+                        .collect();
+                }
+                parts
+            })
+            .filter(|s| s.window.start < s.window.end && s.window.end > from)
+            .chain(dynamic)
             .collect();
 
-        sch.sort_by(|a, b| a.window.start.cmp(&b.window.start));
+        sch.sort_by_key(|a| a.window.start);
         sch
     }
 
+    /// Stored energy in kWh above the depth of discharge limit
+    fn usable_capacity(&self, state: &ControllerInputState) -> f32 {
+        state.capacity * (state.soc - (1.0 - self.dod)).max(0.0)
+    }
+
     pub fn desired_state(
         &self,
         from: DateTime<Utc>,
@@ -148,30 +362,106 @@ impl Controller {
 
         let current_sch = sch
             .first()
-            .ok_or_else(|| ControllerError("No current rate Found".to_owned()))?;
+            .filter(|s| s.window.is_inside(from))
+            .ok_or(ControllerError::NoCurrentRate)?;
         let next_charge = sch
             .iter()
-            .filter(|s| s.rate.charge.charge_enabled())
-            .next()
-            .ok_or_else(|| ControllerError("No next charge rate Found".to_owned()))?;
+            .find(|s| s.rate.charge.charge_enabled())
+            .ok_or(ControllerError::NoChargeRate)?;
+        let next_rate = sch.get(1).ok_or(ControllerError::NoNextRate)?;
+        let charge = &current_sch.rate.charge;
+        let limit_reached =
+            charge.unit_limit > 0 && current_state.window_units >= charge.unit_limit as f32;
 
-        if current_sch.rate.charge.charge_enabled() {
-            // current rate is charger, just charge
+        if let Some(config) = &self.planner {
+            // follow the first step of the plan, the unit limit still stops charging
+            let plan = Planner::new(config).plan(
+                &sch,
+                from,
+                PlanInput {
+                    soc: current_state.soc,
+                    min_soc: 1.0 - self.dod,
+                    capacity: current_state.capacity,
+                    load: current_state.system_load,
+                    max_charge_power: current_state.max_charge_power(),
+                    max_discharge_power: current_state.max_discharge_power(),
+                    export_limit: self.export_limit,
+                    window_units: current_state.window_units,
+                },
+            );
+            let battery_power = match plan.at(from) {
+                Some(s) if s.battery_power < 0.0 && limit_reached => 0.0,
+                Some(s) => s.battery_power,
+                None => 0.0,
+            };
+            let battery_load = battery_power.max(0.0);
             return Ok(ControllerOutputState {
+                disable_charge: !charge.charge_enabled(),
+                disable_feed_in: battery_load == 0.0,
+                soc: current_state.soc,
+                grid_load: (current_state.system_load - battery_power).max(
+                    match current_sch.rate.discharge.mode {
+                        DischargeMode::Export => -self.export_limit,
+                        _ => 0.0,
+                    },
+                ),
+                battery_load,
+                using_capacity: self.usable_capacity(&current_state),
+                reserve_capacity: 0.0,
+                current_rate: current_sch.clone(),
+                next_rate: next_rate.clone(),
+                next_charge: next_charge.clone(),
+                phase_loads: current_state.phase_loads.clone(),
+                phase_compensation: current_state.phase_compensation,
+                window_units: current_state.window_units,
+                plan: Some(plan),
+            });
+        }
+
+        if charge.charge_enabled() {
+            // current rate is charger, charge until the target or the unit limit is reached,
+            // then hold the battery and run the loads from the grid
+            let target = match charge.mode {
+                ChargeMode::Capacity(target) => target,
+                ChargeMode::Disabled => 0.0,
+            };
+            let charge_power = match charge.strategy {
+                _ if current_state.soc >= target || limit_reached => Some(0.0),
+                ChargeStrategy::Immediate => current_state.max_charge_power(),
+                ChargeStrategy::Spread => {
+                    let kwh_needed = (target - current_state.soc) * current_state.capacity;
+                    // back to back price slots of the same rate count as one window
+                    let mut end = current_sch.window.end;
+                    for s in sch.iter().skip(1) {
+                        if s.rate.name != current_sch.rate.name || s.window.start != end {
+                            break;
+                        }
+                        end = s.window.end;
+                    }
+                    let hours = (end - from).num_minutes().max(1) as f32 / 60.0;
+                    let power = (kwh_needed / hours) * 1000.0;
+                    Some(power.min(current_state.max_charge_power().unwrap_or(f32::MAX)))
+                }
+            };
+            Ok(ControllerOutputState {
                 disable_charge: false,
                 disable_feed_in: true,
                 soc: current_state.soc,
-                grid_load: 32_000.0,
+                grid_load: match charge_power {
+                    Some(p) => current_state.system_load + p,
+                    None => 32_000.0,
+                },
                 battery_load: 0.0,
                 using_capacity: 0.0,
                 reserve_capacity: 0.0,
                 current_rate: current_sch.clone(),
-                next_rate: sch
-                    .get(1)
-                    .ok_or_else(|| ControllerError("No next rate found".to_owned()))?
-                    .clone(),
+                next_rate: next_rate.clone(),
                 next_charge: next_charge.clone(),
-            });
+                phase_loads: current_state.phase_loads.clone(),
+                phase_compensation: current_state.phase_compensation,
+                window_units: current_state.window_units,
+                plan: None,
+            })
         } else {
             // we are discharging, use remaining capacity
             let rates_before_charge: Vec<&Schedule> = sch
@@ -182,35 +472,54 @@ impl Controller {
                 .iter()
                 .fold(0f32, |acc, &s| acc + s.rate.reserve);
             let time_until_charge = next_charge.window.start - from;
-            let kwh_capacity = current_state.capacity * self.dod * current_state.soc;
-            let remaining_capacity = (kwh_capacity - reserve).max(0.0);
+            let remaining_capacity = (self.usable_capacity(&current_state) - reserve).max(0.0);
+
+            // stored energy is worth the most expensive import it replaces before the next charge
+            let keep_value = rates_before_charge
+                .iter()
+                .filter(|s| s.rate.discharge.mode != DischargeMode::None)
+                .map(|s| s.rate.unit_cost)
+                .fold(next_charge.rate.unit_cost, f32::max);
+            let exporting = current_sch.rate.discharge.mode == DischargeMode::Export
+                && self.export_limit > 0.0
+                && current_sch.rate.export_price > keep_value
+                && remaining_capacity > 0.0
+                && current_state.soc > (1.0 - self.dod);
 
             let battery_load = match current_sch.rate.discharge.mode {
+                DischargeMode::Export if exporting => {
+                    current_state.system_load + self.export_limit
+                }
+                DischargeMode::Export => current_state.system_load,
                 DischargeMode::Spread => {
                     let hours = time_until_charge.num_minutes() as f32 / 60.0;
                     (remaining_capacity / hours) * 1000.0
                 }
                 DischargeMode::Capacity(v) => current_state.system_load * v,
                 _ => 0.0,
-            }.min(current_sch.rate.discharge.max_power).max(0.0);
+            }.min(current_sch.rate.discharge.max_power)
+                .min(current_state.max_discharge_power().unwrap_or(f32::MAX))
+                .max(0.0);
 
             let disable_feed_in = remaining_capacity == 0.0 || battery_load == 0.0 ||
                 current_state.soc <= (1.0 - self.dod);
-            return Ok(ControllerOutputState {
+            Ok(ControllerOutputState {
                 disable_charge: true,
                 disable_feed_in,
                 soc: current_state.soc,
-                grid_load: (current_state.system_load - battery_load).max(0.0),
+                grid_load: (current_state.system_load - battery_load)
+                    .max(if exporting { -self.export_limit } else { 0.0 }),
                 battery_load,
                 using_capacity: remaining_capacity,
                 reserve_capacity: reserve,
                 current_rate: current_sch.clone(),
-                next_rate: sch
-                    .get(1)
-                    .ok_or_else(|| ControllerError("No next rate found".to_owned()))?
-                    .clone(),
+                next_rate: next_rate.clone(),
                 next_charge: next_charge.clone(),
-            });
+                phase_loads: current_state.phase_loads.clone(),
+                phase_compensation: current_state.phase_compensation,
+                window_units: current_state.window_units,
+                plan: None,
+            })
         }
     }
 }
@@ -218,7 +527,9 @@ impl Controller {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::smart_ess::rate::{ChargeMode, Rate, RateCharge};
+    use crate::smart_ess::rate::{ChargeMode, ChargeStrategy, Rate, RateCharge, RateDischarge};
+    use crate::smart_ess::tariff::{PriceFormat, TariffSource};
+    use crate::smart_ess::usage::UnitUsage;
     use crate::smart_ess::window::{RateTime, RateWindow, ALL_WEEKDAYS};
     use chrono::{Local, TimeZone};
     use std::str::FromStr;
@@ -226,10 +537,15 @@ mod tests {
     fn get_controller() -> Controller {
         Controller {
             dod: 0.9,
+            tariff: None,
+            prices: vec![],
+            planner: None,
+            export_limit: 0.0,
             rates: vec![
                 Rate {
                     name: "Day".to_owned(),
                     unit_cost: 0.0,
+                    export_price: 0.0,
                     windows: vec![RateWindow {
                         start: RateTime::from_str("09:00").unwrap(),
                         end: RateTime::from_str("16:59").unwrap(),
@@ -242,12 +558,14 @@ mod tests {
                     charge: RateCharge {
                         mode: ChargeMode::Disabled,
                         unit_limit: 0,
+                        strategy: ChargeStrategy::Immediate,
                     },
                     reserve: 0.0,
                 },
                 Rate {
                     name: "Peak".to_owned(),
                     unit_cost: 0.0,
+                    export_price: 0.0,
                     windows: vec![RateWindow {
                         start: RateTime::from_str("17:00").unwrap(),
                         end: RateTime::from_str("18:59").unwrap(),
@@ -260,12 +578,14 @@ mod tests {
                     charge: RateCharge {
                         mode: ChargeMode::Disabled,
                         unit_limit: 0,
+                        strategy: ChargeStrategy::Immediate,
                     },
                     reserve: 0.0,
                 },
                 Rate {
                     name: "Night".to_owned(),
                     unit_cost: 0.0,
+                    export_price: 0.0,
                     windows: vec![RateWindow {
                         start: RateTime::from_str("23:00").unwrap(),
                         end: RateTime::from_str("08:59").unwrap(),
@@ -278,6 +598,7 @@ mod tests {
                     charge: RateCharge {
                         mode: ChargeMode::Capacity(1.0),
                         unit_limit: 0,
+                        strategy: ChargeStrategy::Immediate,
                     },
                     reserve: 0.0,
                 },
@@ -288,19 +609,19 @@ mod tests {
     #[test]
     fn schedule() {
         let controller = get_controller();
-        let from = Local.ymd(2022, 05, 03).and_hms(2, 0, 0).with_timezone(&Utc);
+        let from = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
         let sch = controller.get_schedule(from);
-        let next = sch.get(0).unwrap();
+        let next = sch.first().unwrap();
 
-        assert_eq!(next.window.start, Local.ymd(2022, 05, 02).and_hms(23, 0, 0));
+        assert_eq!(next.window.start, Local.with_ymd_and_hms(2022, 5, 2, 23, 0, 0).unwrap());
     }
 
     #[test]
     fn min_soc() {
         let controller = get_controller();
         let from = Local
-            .ymd(2022, 05, 03)
-            .and_hms(17, 30, 0)
+            .with_ymd_and_hms(2022, 5, 3, 17, 30, 0)
+            .unwrap()
             .with_timezone(&Utc);
 
         let state_at_dod = controller
@@ -311,6 +632,7 @@ mod tests {
                     soc: 1.0 - controller.dod,
                     capacity: 4.0,
                     voltage: 0.0,
+                    ..Default::default()
                 },
             )
             .unwrap();
@@ -320,8 +642,8 @@ mod tests {
             DischargeMode::Capacity(1.0),
             "Is peak discharge"
         );
-        assert_eq!(
-            state_at_dod.disable_feed_in, true,
+        assert!(
+            state_at_dod.disable_feed_in,
             "Disable feed-in when at min state of charge {:?}", state_at_dod
         );
 
@@ -333,13 +655,371 @@ mod tests {
                     soc: 1.1 - controller.dod,
                     capacity: 4.0,
                     voltage: 0.0,
+                    ..Default::default()
                 },
             )
             .unwrap();
 
-        assert_eq!(
-            state_above_dod.disable_feed_in, false,
+        assert!(
+            !state_above_dod.disable_feed_in,
             "Above DoD {:?}", state_above_dod
         );
     }
+
+    #[test]
+    fn bms_limits() {
+        let controller = get_controller();
+        let input = ControllerInputState {
+            system_load: 1000.0,
+            soc: 0.5,
+            capacity: 4.0,
+            voltage: 50.0,
+            charge_current_limit: Some(20.0),
+            discharge_current_limit: Some(1.0),
+            ..Default::default()
+        };
+
+        let peak = Local.with_ymd_and_hms(2022, 5, 3, 17, 30, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(peak, input.clone()).unwrap();
+        assert_eq!(state.battery_load, 50.0, "limited by DCL");
+        assert_eq!(state.grid_load, 950.0);
+
+        let night = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(night, input.clone()).unwrap();
+        assert_eq!(state.grid_load, 2000.0, "load plus CCL");
+
+        let state = controller
+            .desired_state(night, ControllerInputState { voltage: 0.0, ..input })
+            .unwrap();
+        assert_eq!(state.grid_load, 32_000.0, "no limit without voltage");
+    }
+
+    #[test]
+    fn phase_loads() {
+        let controller = get_controller();
+        let input = ControllerInputState {
+            system_load: 1500.0,
+            soc: 0.9,
+            capacity: 4.0,
+            phase_loads: vec![1000.0, 400.0, 100.0],
+            ..Default::default()
+        };
+
+        // peak discharge is capped at 100W
+        let peak = Local.with_ymd_and_hms(2022, 5, 3, 17, 30, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(peak, input.clone()).unwrap();
+        let loads = state.grid_loads();
+        assert_eq!(loads.len(), 3);
+        assert_eq!(loads.iter().sum::<f32>(), state.grid_load);
+        assert!((loads[2] - 100.0 * (1.0 - 100.0 / 1500.0)).abs() < 0.01, "proportional {:?}", loads);
+
+        let state = controller
+            .desired_state(peak, ControllerInputState { phase_compensation: true, ..input.clone() })
+            .unwrap();
+        let loads = state.grid_loads();
+        assert!((loads[2] - (100.0 - 100.0 / 3.0)).abs() < 0.01, "equal share {:?}", loads);
+
+        // charging adds the same power on each phase
+        let night = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(night, input).unwrap();
+        let loads = state.grid_loads();
+        assert_eq!(loads[0] - loads[1], 600.0);
+        assert!((loads.iter().sum::<f32>() - 32_000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn no_charge_rate() {
+        let mut controller = get_controller();
+        controller.rates.retain(|r| !r.charge.charge_enabled());
+        let from = Local
+            .with_ymd_and_hms(2022, 5, 3, 12, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let state = controller.desired_state(
+            from,
+            ControllerInputState {
+                system_load: 1000.0,
+                soc: 0.5,
+                capacity: 4.0,
+                voltage: 0.0,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(state, Err(ControllerError::NoChargeRate)));
+        assert!(matches!(
+            controller.next_charge(from),
+            Err(ControllerError::NoChargeRate)
+        ));
+    }
+
+    #[test]
+    fn charge_target() {
+        let controller = get_controller();
+        let night = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let input = ControllerInputState {
+            system_load: 1000.0,
+            soc: 0.95,
+            capacity: 4.0,
+            voltage: 50.0,
+            charge_current_limit: Some(20.0),
+            ..Default::default()
+        };
+
+        let state = controller.desired_state(night, input.clone()).unwrap();
+        assert_eq!(state.grid_load, 2000.0, "below target");
+
+        let state = controller
+            .desired_state(night, ControllerInputState { soc: 1.0, ..input })
+            .unwrap();
+        assert_eq!(state.grid_load, 1000.0, "hold at target");
+        assert!(state.disable_feed_in);
+    }
+
+    #[test]
+    fn unit_limit() {
+        let mut controller = get_controller();
+        controller.rates[2].charge.unit_limit = 5;
+        let night = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let input = ControllerInputState {
+            system_load: 1000.0,
+            soc: 0.5,
+            capacity: 4.0,
+            voltage: 50.0,
+            charge_current_limit: Some(20.0),
+            window_units: 4.9,
+            ..Default::default()
+        };
+
+        let state = controller.desired_state(night, input.clone()).unwrap();
+        assert_eq!(state.grid_load, 2000.0, "below limit");
+
+        let state = controller
+            .desired_state(night, ControllerInputState { window_units: 5.0, ..input })
+            .unwrap();
+        assert_eq!(state.grid_load, 1000.0, "limit reached");
+    }
+
+    #[test]
+    fn charge_spread() {
+        let mut controller = get_controller();
+        controller.rates[2].charge.strategy = ChargeStrategy::Spread;
+        let input = ControllerInputState {
+            system_load: 1000.0,
+            soc: 0.5,
+            capacity: 4.0,
+            voltage: 50.0,
+            charge_current_limit: Some(20.0),
+            ..Default::default()
+        };
+
+        // 2kWh needed with 7h left in the window
+        let early = Local.with_ymd_and_hms(2022, 5, 3, 1, 59, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(early, input.clone()).unwrap();
+        assert!((state.grid_load - (1000.0 + 2000.0 / 7.0)).abs() < 0.01, "{:?}", state);
+
+        // limited by CCL close to the end of the window
+        let late = Local.with_ymd_and_hms(2022, 5, 3, 8, 29, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(late, input.clone()).unwrap();
+        assert_eq!(state.grid_load, 2000.0);
+
+        // no CCL, spread power is still used
+        let state = controller
+            .desired_state(early, ControllerInputState { charge_current_limit: None, ..input.clone() })
+            .unwrap();
+        assert!(state.grid_load < 2000.0, "{:?}", state);
+
+        let state = controller
+            .desired_state(early, ControllerInputState { soc: 1.0, ..input })
+            .unwrap();
+        assert_eq!(state.grid_load, 1000.0, "target reached");
+    }
+
+    fn get_dynamic_controller() -> Controller {
+        let mut controller = get_controller();
+        controller.tariff = Some(DynamicTariff {
+            source: TariffSource::File("prices.json".to_owned()),
+            format: PriceFormat::Json,
+            charge_below: 0.1,
+            discharge_above: 0.3,
+            charge: controller.rates[2].charge,
+            discharge: controller.rates[1].discharge,
+            normal: controller.rates[0].discharge,
+        });
+        controller
+    }
+
+    #[test]
+    fn dynamic_tariff() {
+        let mut controller = get_dynamic_controller();
+        let at = |h, m| Local.with_ymd_and_hms(2022, 5, 3, h, m, 0).unwrap().with_timezone(&Utc);
+        controller.set_prices(vec![
+            PriceSlot { start: at(2, 30), end: at(3, 0), unit_cost: 0.35, export_price: 0.0 },
+            PriceSlot { start: at(3, 0), end: at(3, 30), unit_cost: 0.05, export_price: 0.0 },
+        ]);
+
+        // fixed night rate runs until the first published slot
+        let sch = controller.get_schedule(at(2, 0));
+        let names: Vec<&str> = sch.iter().take(4).map(|s| s.rate.name.as_str()).collect();
+        assert_eq!(names, vec!["Night", "Expensive", "Cheap", "Night"]);
+        assert_eq!(sch[0].window.end, at(2, 30));
+        assert_eq!(sch[3].window.start, at(3, 30), "night rate resumes after the prices");
+
+        let state = controller
+            .desired_state(
+                at(2, 45),
+                ControllerInputState {
+                    system_load: 1000.0,
+                    soc: 0.5,
+                    capacity: 4.0,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(state.current_rate.rate.name, "Expensive");
+        assert_eq!(state.next_charge.window.start, at(3, 0));
+        assert_eq!(state.battery_load, 100.0, "peak discharge settings");
+
+        // prices published until 23:30, inside the fixed night rate from 23:00
+        controller.set_prices(vec![PriceSlot {
+            start: at(22, 30),
+            end: at(23, 30),
+            unit_cost: 0.35,
+            export_price: 0.0,
+        }]);
+        let sch = controller.get_schedule(at(22, 0));
+        let names: Vec<&str> = sch.iter().take(2).map(|s| s.rate.name.as_str()).collect();
+        assert_eq!(names, vec!["Expensive", "Night"]);
+        assert_eq!(sch[1].window.start, at(23, 30), "clipped, not dropped");
+
+        // the night rate fills a gap in the price feed
+        controller.set_prices(vec![
+            PriceSlot { start: at(2, 30), end: at(3, 0), unit_cost: 0.35, export_price: 0.0 },
+            PriceSlot { start: at(4, 0), end: at(4, 30), unit_cost: 0.35, export_price: 0.0 },
+        ]);
+        let sch = controller.get_schedule(at(2, 45));
+        let names: Vec<&str> = sch.iter().take(4).map(|s| s.rate.name.as_str()).collect();
+        assert_eq!(names, vec!["Expensive", "Night", "Expensive", "Night"]);
+        assert_eq!((sch[1].window.start, sch[1].window.end), (at(3, 0), at(4, 0)));
+
+        // nothing covers the evening between the peak and night rates
+        assert!(matches!(
+            controller.desired_state(at(20, 0), ControllerInputState::default()),
+            Err(ControllerError::NoCurrentRate)
+        ));
+    }
+
+    #[test]
+    fn dynamic_usage_window() {
+        let mut controller = get_dynamic_controller();
+        let at = |h, m| Local.with_ymd_and_hms(2022, 5, 3, h, m, 0).unwrap().with_timezone(&Utc);
+        let cheap = |start, end| PriceSlot { start, end, unit_cost: 0.05, export_price: 0.0 };
+
+        // back to back cheap slots share one unit limit
+        controller.set_prices(vec![cheap(at(2, 0), at(2, 30)), cheap(at(2, 30), at(3, 0))]);
+        let sch = controller.get_schedule(at(2, 0));
+        assert_eq!(sch[0].usage_window, sch[1].usage_window);
+        assert_eq!(sch[1].usage_window.start, at(2, 0));
+
+        let mut usage = UnitUsage::default();
+        assert!(usage.record(&sch[0], at(2, 25), 0.0));
+        usage.record(&sch[0], at(2, 30), 12_000.0);
+        let sch = controller.get_schedule(at(2, 35));
+        assert!(!usage.record(&sch[0], at(2, 35), 12_000.0), "same window in the next slot");
+        assert!((usage.units(at(2, 35)) - 2.0).abs() < 0.0001);
+
+        // the fixed night rate keeps its window start while prices are extended
+        controller.set_prices(vec![cheap(at(22, 30), at(23, 30))]);
+        let night_after = |sch: Vec<Schedule>| sch.into_iter().find(|s| s.rate.name == "Night").unwrap();
+        let night = night_after(controller.get_schedule(at(22, 0)));
+        assert_eq!(night.window.start, at(23, 30));
+        controller.set_prices(vec![cheap(at(22, 30), at(23, 30)), cheap(at(23, 30), at(23, 45))]);
+        let extended = night_after(controller.get_schedule(at(22, 0)));
+        assert_eq!(extended.window.start, at(23, 45));
+        assert_eq!(extended.usage_window, night.usage_window);
+        assert_eq!(night.usage_window.start, at(23, 0));
+    }
+
+    #[test]
+    fn planner() {
+        let mut controller = get_controller();
+        controller.rates[0].unit_cost = 0.2;
+        controller.rates[1].unit_cost = 0.4;
+        controller.rates[2].unit_cost = 0.1;
+        controller.planner = Some(PlannerConfig {
+            step_minutes: 30,
+            horizon_hours: 24,
+            soc_levels: 90,
+            charge_efficiency: 1.0,
+            discharge_efficiency: 1.0,
+            max_charge_power: 2000.0,
+            max_discharge_power: 2000.0,
+            load_profile: vec![],
+        });
+        let input = ControllerInputState {
+            system_load: 1000.0,
+            soc: 0.5,
+            capacity: 10.0,
+            ..Default::default()
+        };
+
+        // the battery is filled at night and covers the day rate
+        let night = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
+        let state = controller.desired_state(night, input.clone()).unwrap();
+        let plan = state.plan.as_ref().unwrap();
+        assert_eq!(plan.steps[0].start, night);
+        assert!(!state.disable_charge);
+        assert_eq!(state.grid_load, 1000.0 - plan.steps[0].battery_power);
+        let charged = plan.steps.iter().filter(|s| s.rate == "Night").map(|s| s.soc).fold(0.0, f32::max);
+        assert!(charged > 0.9, "enough for the 8kWh day {}", plan);
+        let day = plan.steps.iter().filter(|s| s.rate == "Day");
+        assert!(day.clone().count() == 16 && day.clone().all(|s| s.grid_power == 0.0), "{}", plan);
+
+        // the unit limit stops planned charging
+        controller.rates[2].charge.unit_limit = 1;
+        let state = controller
+            .desired_state(night, ControllerInputState { window_units: 1.0, ..input })
+            .unwrap();
+        assert_eq!(state.grid_load, 1000.0);
+        assert!(state.disable_feed_in);
+    }
+
+    #[test]
+    fn export() {
+        let mut controller = get_controller();
+        controller.rates[1].discharge = RateDischarge {
+            mode: DischargeMode::Export,
+            max_power: 3000.0,
+        };
+        controller.rates[1].export_price = 0.3;
+        controller.rates[2].unit_cost = 0.1;
+        let peak = Local.with_ymd_and_hms(2022, 5, 3, 17, 30, 0).unwrap().with_timezone(&Utc);
+        let input = ControllerInputState {
+            system_load: 500.0,
+            soc: 0.8,
+            capacity: 10.0,
+            ..Default::default()
+        };
+
+        let state = controller.desired_state(peak, input.clone()).unwrap();
+        assert_eq!(state.grid_load, 0.0, "no export without a limit");
+        assert_eq!(state.battery_load, 500.0);
+
+        controller.export_limit = 2000.0;
+        let state = controller.desired_state(peak, input.clone()).unwrap();
+        assert_eq!(state.battery_load, 2500.0);
+        assert_eq!(state.grid_load, -2000.0);
+        assert!(!state.disable_feed_in);
+
+        // recharging costs more than the export pays
+        controller.rates[2].unit_cost = 0.35;
+        let state = controller.desired_state(peak, input.clone()).unwrap();
+        assert_eq!(state.grid_load, 0.0);
+
+        controller.rates[2].unit_cost = 0.1;
+        let state = controller
+            .desired_state(peak, ControllerInputState { soc: 0.1, ..input })
+            .unwrap();
+        assert!(state.grid_load >= 0.0 && state.disable_feed_in, "at DoD limit {:?}", state);
+    }
 }
@@ -0,0 +1,569 @@
+use std::fmt::{Display, Formatter};
+
+use chrono::{DateTime, Duration, DurationRound, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::smart_ess::rate::{ChargeMode, DischargeMode};
+use crate::smart_ess::Schedule;
+
+/// Settings for the cost optimising [Planner], replaces the rule based
+/// dispatch and per rate reserves when configured
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannerConfig {
+    /// Length of each plan step in minutes
+    #[serde(default = "default_step")]
+    pub step_minutes: u16,
+
+    /// How far ahead to plan in hours, limited by the published schedule
+    #[serde(default = "default_horizon")]
+    pub horizon_hours: u16,
+
+    /// Number of SoC levels between the DoD limit and full, more levels
+    /// follow the load more closely
+    #[serde(default = "default_levels")]
+    pub soc_levels: u16,
+
+    /// Fraction of grid energy stored when charging
+    #[serde(default = "default_efficiency")]
+    pub charge_efficiency: f32,
+
+    /// Fraction of stored energy delivered when discharging
+    #[serde(default = "default_efficiency")]
+    pub discharge_efficiency: f32,
+
+    /// Inverter charge power limit in watts, lowered further by the BMS
+    pub max_charge_power: f32,
+
+    /// Inverter discharge power limit in watts, lowered further by the BMS
+    /// and the rate's max power
+    pub max_discharge_power: f32,
+
+    /// Expected load in watts for each local hour of the day, the current
+    /// load is used for every hour when empty
+    #[serde(default)]
+    pub load_profile: Vec<f32>,
+}
+
+fn default_step() -> u16 {
+    30
+}
+
+fn default_horizon() -> u16 {
+    24
+}
+
+fn default_levels() -> u16 {
+    50
+}
+
+fn default_efficiency() -> f32 {
+    0.95
+}
+
+/// Battery state and limits the plan starts from
+#[derive(Debug, Clone, Copy)]
+pub struct PlanInput {
+    /// Battery state of charge 0-1
+    pub soc: f32,
+
+    /// Lowest allowed state of charge 0-1
+    pub min_soc: f32,
+
+    /// Battery capacity in kWh
+    pub capacity: f32,
+
+    /// Load right now in watts, used for the first step
+    pub load: f32,
+
+    /// BMS charge power limit in watts
+    pub max_charge_power: Option<f32>,
+
+    /// BMS discharge power limit in watts
+    pub max_discharge_power: Option<f32>,
+
+    /// Most power in watts fed into the grid during [DischargeMode::Export] rates
+    pub export_limit: f32,
+
+    /// Units in kWh already charged in the current rate window, counted
+    /// against its [crate::smart_ess::rate::RateCharge::unit_limit]
+    pub window_units: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlanStep {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+
+    /// Name of the rate the step falls in
+    pub rate: String,
+    pub unit_cost: f32,
+
+    /// Expected load in watts
+    pub load: f32,
+
+    /// Battery power in watts, positive when discharging and negative when charging
+    pub battery_power: f32,
+
+    /// Grid import in watts, negative when exporting
+    pub grid_power: f32,
+
+    /// State of charge at the end of the step
+    pub soc: f32,
+
+    /// Cost of the grid import less export revenue
+    pub cost: f32,
+}
+
+/// SoC trajectory with the lowest cost over the schedule
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Plan {
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    /// Total cost of grid import less export revenue over the plan
+    pub fn cost(&self) -> f32 {
+        self.steps.iter().map(|s| s.cost).sum()
+    }
+
+    /// Step covering `t`
+    pub fn at(&self, t: DateTime<Utc>) -> Option<&PlanStep> {
+        self.steps.iter().find(|s| s.start <= t && t < s.end)
+    }
+}
+
+impl Display for PlanStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} @ {}: {:.0}W battery, {:.0}W grid, SoC {:.0}%",
+            self.start.with_timezone(&Local).format("%H:%M"),
+            self.rate,
+            self.unit_cost,
+            self.battery_power,
+            self.grid_power,
+            self.soc * 100.0
+        )
+    }
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Plan cost: {:.2}", self.cost())?;
+        for s in &self.steps {
+            write!(f, "\n{}", s)?;
+        }
+        Ok(())
+    }
+}
+
+/// One planning step before it is solved
+struct Slot<'a> {
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    sch: &'a Schedule,
+    load: f32,
+}
+
+/// Dynamic programming over discrete SoC levels, finding the charge and
+/// discharge per step with the lowest grid import cost less export revenue
+pub struct Planner<'a> {
+    config: &'a PlannerConfig,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(config: &'a PlannerConfig) -> Self {
+        Self { config }
+    }
+
+    /// Expected load at `t`
+    fn load_at(&self, t: DateTime<Utc>, input: &PlanInput) -> f32 {
+        let profile = &self.config.load_profile;
+        if profile.is_empty() {
+            input.load
+        } else {
+            profile[t.with_timezone(&Local).hour() as usize % profile.len()]
+        }
+    }
+
+    /// Split the schedule into steps aligned to the step length, stopping at
+    /// the horizon or the first gap in the schedule. Each step uses the
+    /// schedule overlapping it the most
+    fn slots<'s>(&self, sch: &'s [Schedule], from: DateTime<Utc>, input: &PlanInput) -> Vec<Slot<'s>> {
+        let step = Duration::try_minutes(self.config.step_minutes.max(1) as i64).unwrap();
+        let until = from + Duration::try_hours(self.config.horizon_hours as i64).unwrap();
+
+        let mut ret = vec![];
+        let mut start = from;
+        while start < until {
+            let end = (start.duration_trunc(step).unwrap() + step).min(until);
+            let overlap = |s: &&Schedule| s.window.end.min(end) - s.window.start.max(start);
+            let sch = match sch
+                .iter()
+                .filter(|s| overlap(s) > Duration::zero())
+                .max_by_key(overlap)
+            {
+                Some(s) => s,
+                // skip a gap between fixed windows at the start
+                None if ret.is_empty() => {
+                    start = end;
+                    continue;
+                }
+                None => break,
+            };
+            let load = if ret.is_empty() {
+                input.load
+            } else {
+                self.load_at(start, input)
+            };
+            ret.push(Slot { start, end, sch, load });
+            start = end;
+        }
+        ret
+    }
+
+    pub fn plan(&self, sch: &[Schedule], from: DateTime<Utc>, input: PlanInput) -> Plan {
+        let slots = self.slots(sch, from, &input);
+        let levels = self.config.soc_levels.max(1) as usize;
+        let min_soc = input.min_soc.clamp(0.0, 1.0);
+        let soc_of = |l: usize| min_soc + (1.0 - min_soc) * l as f32 / levels as f32;
+        let kwh_per_level = input.capacity * (1.0 - min_soc) / levels as f32;
+        let start_level = (((input.soc - min_soc) / (1.0 - min_soc).max(f32::EPSILON))
+            * levels as f32)
+            .round()
+            .clamp(0.0, levels as f32) as usize;
+
+        let max_charge = self
+            .config
+            .max_charge_power
+            .min(input.max_charge_power.unwrap_or(f32::MAX));
+        let max_discharge = self
+            .config
+            .max_discharge_power
+            .min(input.max_discharge_power.unwrap_or(f32::MAX));
+
+        // energy left at the end is worth what it costs to charge it again
+        let refill_cost = slots
+            .iter()
+            .filter(|s| s.sch.rate.charge.charge_enabled())
+            .map(|s| s.sch.rate.unit_cost)
+            .fold(f32::MAX, f32::min);
+        let refill_cost = if refill_cost == f32::MAX {
+            0.0
+        } else {
+            refill_cost / self.config.charge_efficiency
+        };
+
+        // levels each slot's rate window may still charge from the grid
+        let caps: Vec<Option<usize>> = slots
+            .iter()
+            .map(|s| {
+                let limit = s.sch.rate.charge.unit_limit;
+                if limit == 0 {
+                    return None;
+                }
+                let mut units = limit as f32;
+                if s.sch.usage_window.is_inside(from) {
+                    units -= input.window_units;
+                }
+                let stored = units.max(0.0) * self.config.charge_efficiency;
+                let left = stored / kwh_per_level.max(f32::EPSILON);
+                Some(((left + 0.001).floor() as usize).min(levels))
+            })
+            .collect();
+        let cdim = caps.iter().flatten().max().map_or(1, |c| c + 1);
+        let n = slots.len();
+        let same_window = |t: usize| {
+            t + 1 < n
+                && slots[t].sch.rate.name == slots[t + 1].sch.rate.name
+                && slots[t].sch.usage_window.start == slots[t + 1].sch.usage_window.start
+        };
+        // levels charged so far in the window after moving from level i to j in slot t,
+        // or None if that would go over the unit limit
+        let charged = |t: usize, c: usize, i: usize, j: usize| -> Option<usize> {
+            let c = c + j.saturating_sub(i);
+            match caps[t] {
+                Some(cap) if c > cap => None,
+                Some(_) if same_window(t) => Some(c),
+                _ => Some(0),
+            }
+        };
+
+        // cost and action of moving from level i to level j in a slot
+        let transition = |slot: &Slot, i: usize, j: usize| -> Option<(f32, f32, f32)> {
+            let hours = (slot.end - slot.start).num_seconds() as f32 / 3600.0;
+            let rate = &slot.sch.rate;
+            let stored = (j as f32 - i as f32) * kwh_per_level;
+            let battery_power = if j > i {
+                let target = match rate.charge.mode {
+                    ChargeMode::Capacity(t) => t,
+                    ChargeMode::Disabled => return None,
+                };
+                if soc_of(j) > target + f32::EPSILON {
+                    return None;
+                }
+                -stored / self.config.charge_efficiency / hours * 1000.0
+            } else if j < i {
+                if rate.discharge.mode == DischargeMode::None {
+                    return None;
+                }
+                -stored * self.config.discharge_efficiency / hours * 1000.0
+            } else {
+                0.0
+            };
+            let export_limit = if rate.discharge.mode == DischargeMode::Export {
+                input.export_limit
+            } else {
+                0.0
+            };
+            if -battery_power > max_charge + 0.01
+                || battery_power > max_discharge.min(rate.discharge.max_power) + 0.01
+                || battery_power > slot.load.max(0.0) + export_limit + 0.01
+            {
+                return None;
+            }
+            let grid_power = (slot.load - battery_power).max(-export_limit);
+            let price = if grid_power < 0.0 {
+                rate.export_price
+            } else {
+                rate.unit_cost
+            };
+            Some((grid_power * hours / 1000.0 * price, battery_power, grid_power))
+        };
+
+        // cost[t][l * cdim + c] is the lowest cost from the start of slot t at
+        // level l, with c levels already charged in the slot's rate window
+        let mut cost = vec![vec![f32::MAX; (levels + 1) * cdim]; n + 1];
+        let mut next = vec![vec![0usize; (levels + 1) * cdim]; n];
+        for (k, c) in cost[n].iter_mut().enumerate() {
+            *c = -((k / cdim) as f32) * kwh_per_level * refill_cost;
+        }
+        for t in (0..n).rev() {
+            for i in 0..=levels {
+                for c in 0..cdim {
+                    for j in 0..=levels {
+                        let k = match charged(t, c, i, j) {
+                            Some(c) => j * cdim + c,
+                            None => continue,
+                        };
+                        if cost[t + 1][k] == f32::MAX {
+                            continue;
+                        }
+                        if let Some((step, _, _)) = transition(&slots[t], i, j) {
+                            let total = step + cost[t + 1][k];
+                            if total < cost[t][i * cdim + c] {
+                                cost[t][i * cdim + c] = total;
+                                next[t][i * cdim + c] = j;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut steps = vec![];
+        let mut level = start_level;
+        let mut window_levels = 0;
+        for (t, slot) in slots.iter().enumerate() {
+            // idle is always possible unless the load is negative
+            let k = level * cdim + window_levels;
+            let j = if cost[t][k] == f32::MAX { level } else { next[t][k] };
+            window_levels = charged(t, window_levels, level, j).unwrap_or(0);
+            let (c, battery_power, grid_power) =
+                transition(slot, level, j).unwrap_or((0.0, 0.0, slot.load.max(0.0)));
+            steps.push(PlanStep {
+                start: slot.start,
+                end: slot.end,
+                rate: slot.sch.rate.name.clone(),
+                unit_cost: slot.sch.rate.unit_cost,
+                load: slot.load,
+                battery_power,
+                grid_power,
+                soc: soc_of(j),
+                cost: c,
+            });
+            level = j;
+        }
+        Plan { steps }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smart_ess::rate::{ChargeStrategy, Rate, RateCharge, RateDischarge};
+    use crate::smart_ess::window::RateWindowAbsolute;
+    use chrono::TimeZone;
+
+    fn config() -> PlannerConfig {
+        PlannerConfig {
+            step_minutes: 30,
+            horizon_hours: 24,
+            soc_levels: 80,
+            charge_efficiency: 1.0,
+            discharge_efficiency: 1.0,
+            max_charge_power: 3000.0,
+            max_discharge_power: 3000.0,
+            load_profile: vec![],
+        }
+    }
+
+    fn schedule(name: &str, start: u32, end: u32, unit_cost: f32, charge: bool) -> Schedule {
+        let window = RateWindowAbsolute {
+            start: Utc.with_ymd_and_hms(2024, 5, 3, start, 0, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2024, 5, 3, end, 0, 0).unwrap(),
+        };
+        Schedule {
+            rate: Rate {
+                name: name.to_owned(),
+                unit_cost,
+                export_price: 0.0,
+                windows: vec![],
+                discharge: RateDischarge {
+                    mode: if charge { DischargeMode::None } else { DischargeMode::Spread },
+                    max_power: 3000.0,
+                },
+                charge: RateCharge {
+                    mode: if charge { ChargeMode::Capacity(1.0) } else { ChargeMode::Disabled },
+                    unit_limit: 0,
+                    strategy: ChargeStrategy::Immediate,
+                },
+                reserve: 0.0,
+            },
+            usage_window: window.clone(),
+            window,
+        }
+    }
+
+    fn input(soc: f32) -> PlanInput {
+        PlanInput {
+            soc,
+            min_soc: 0.2,
+            capacity: 10.0,
+            load: 1000.0,
+            max_charge_power: None,
+            max_discharge_power: None,
+            export_limit: 0.0,
+            window_units: 0.0,
+        }
+    }
+
+    #[test]
+    fn shift_to_cheap() {
+        let sch = vec![
+            schedule("Cheap", 0, 4, 0.1, true),
+            schedule("Expensive", 4, 8, 0.4, false),
+            schedule("Normal", 8, 12, 0.2, false),
+        ];
+        let from = sch[0].window.start;
+        let plan = Planner::new(&config()).plan(&sch, from, input(0.2));
+
+        assert_eq!(plan.steps.len(), 24, "stops at the end of the schedule");
+        assert_eq!(plan.steps[0].start, from);
+        assert_eq!(plan.steps[0].end, from + Duration::try_minutes(30).unwrap());
+
+        // 4kWh for the expensive rate and 4kWh for the normal rate
+        let charged: f32 = plan.steps[..8].iter().map(|s| -s.battery_power / 2000.0).sum();
+        assert!((charged - 8.0).abs() < 0.01, "{}", plan);
+        assert!(plan.steps[8..].iter().all(|s| s.grid_power.abs() < 0.01), "{}", plan);
+        assert!((plan.cost() - (8.0 + 4.0) * 0.1).abs() < 0.01, "{}", plan);
+        assert!(plan.steps.iter().all(|s| s.grid_power >= 0.0 && s.soc >= 0.2));
+    }
+
+    #[test]
+    fn limits() {
+        let sch = vec![
+            schedule("Cheap", 0, 2, 0.1, true),
+            schedule("Expensive", 2, 4, 0.4, false),
+        ];
+        let from = sch[0].window.start;
+        let plan = Planner::new(&config()).plan(
+            &sch,
+            from,
+            PlanInput {
+                max_charge_power: Some(1500.0),
+                ..input(0.2)
+            },
+        );
+        for s in &plan.steps[..4] {
+            assert!(s.battery_power >= -1500.0 - 0.01, "BMS charge limit {}", plan);
+        }
+        for s in &plan.steps[4..] {
+            assert!(s.battery_power <= 1000.0 + 0.01, "no export {}", plan);
+        }
+
+        // no charge rate, only discharge down to the DoD limit
+        let sch = vec![schedule("Expensive", 0, 12, 0.4, false)];
+        let plan = Planner::new(&config()).plan(&sch, from, input(0.5));
+        assert!(plan.steps.iter().all(|s| s.battery_power >= 0.0));
+        assert!((plan.steps.last().unwrap().soc - 0.2).abs() < 0.01, "{}", plan);
+        assert!(plan.at(from + Duration::try_hours(1).unwrap()).is_some());
+        assert!(plan.at(from + Duration::try_hours(13).unwrap()).is_none());
+    }
+
+    #[test]
+    fn unit_limit() {
+        let mut sch = vec![
+            schedule("Cheap", 0, 2, 0.1, true),
+            schedule("Cheap", 2, 4, 0.1, true),
+            schedule("Expensive", 4, 8, 0.4, false),
+        ];
+        // back to back price slots share one window and one limit
+        let window = RateWindowAbsolute {
+            start: sch[0].window.start,
+            end: sch[1].window.end,
+        };
+        for s in &mut sch[..2] {
+            s.rate.charge.unit_limit = 3;
+            s.usage_window = window.clone();
+        }
+        let from = sch[0].window.start;
+        let charged = |plan: &Plan| -> f32 {
+            plan.steps.iter().map(|s| (-s.battery_power).max(0.0) / 2000.0).sum()
+        };
+
+        let plan = Planner::new(&config()).plan(&sch, from, input(0.2));
+        assert!((charged(&plan) - 3.0).abs() < 0.01, "{}", plan);
+
+        // units already charged in this window count against the limit
+        let plan = Planner::new(&config()).plan(
+            &sch,
+            from,
+            PlanInput {
+                window_units: 1.0,
+                ..input(0.2)
+            },
+        );
+        assert!((charged(&plan) - 2.0).abs() < 0.01, "{}", plan);
+        assert!(plan.steps[8..].iter().all(|s| s.battery_power >= 0.0), "{}", plan);
+    }
+
+    #[test]
+    fn export() {
+        let mut sch = vec![
+            schedule("Cheap", 0, 4, 0.1, true),
+            schedule("Expensive", 4, 8, 0.4, false),
+        ];
+        sch[1].rate.discharge.mode = DischargeMode::Export;
+        sch[1].rate.export_price = 0.3;
+        let from = sch[0].window.start;
+
+        // without an export limit the battery only covers the load
+        let plan = Planner::new(&config()).plan(&sch, from, input(0.2));
+        assert!(plan.steps.iter().all(|s| s.grid_power >= 0.0), "{}", plan);
+
+        let plan = Planner::new(&config()).plan(
+            &sch,
+            from,
+            PlanInput {
+                export_limit: 2000.0,
+                ..input(0.2)
+            },
+        );
+        // charge at 0.1 and sell at 0.3, limited by charge power and capacity
+        let exported: f32 = plan.steps.iter().map(|s| (-s.grid_power).max(0.0) / 2000.0).sum();
+        assert!(exported > 3.9, "{}", plan);
+        assert!(plan.steps.iter().all(|s| s.grid_power >= -2000.01), "{}", plan);
+        assert!(plan.cost() < 4.0 * 0.1, "{}", plan);
+    }
+}
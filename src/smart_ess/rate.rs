@@ -10,6 +10,10 @@ pub struct Rate {
     /// Fiat cost per 1 kW/h
     pub unit_cost: f32,
 
+    /// Fiat paid per 1 kW/h fed into the grid
+    #[serde(default)]
+    pub export_price: f32,
+
     /// Rate start and end times
     pub windows: Vec<RateWindow>,
 
@@ -41,6 +45,10 @@ pub enum DischargeMode {
 
     /// Drain capacity dynamically until to the end of the rate window
     Spread,
+
+    /// Sell stored energy to the grid at max power while the export price is
+    /// more than the energy is worth to later rates, otherwise cover the load
+    Export,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -48,8 +56,23 @@ pub struct RateCharge {
     /// Charger mode
     pub mode: ChargeMode,
 
-    /// Limit number of units that can be consumed by the charger in this rate.
+    /// Limit number of units (kWh) that can be consumed by the charger in each
+    /// window of this rate, 0 for no limit.
     pub unit_limit: u16,
+
+    /// How charge power is chosen within the window
+    #[serde(default)]
+    pub strategy: ChargeStrategy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChargeStrategy {
+    /// Charge as fast as the BMS allows from the start of the window
+    #[default]
+    Immediate,
+
+    /// Spread charging to reach the target at the end of the rate window
+    Spread,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
@@ -57,7 +80,7 @@ pub enum ChargeMode {
     /// Charger is disabled
     Disabled,
 
-    /// target minimum battery capacity
+    /// target minimum battery capacity, charging stops once it is reached
     Capacity(f32),
 }
 
@@ -66,10 +89,9 @@ impl Rate {
         let mut ret: Vec<RateWindowAbsolute> = self
             .windows
             .iter()
-            .map(|w| w.schedule(from))
-            .flatten()
+            .flat_map(|w| w.schedule(from))
             .collect();
-        ret.sort_by(|a, b| a.start.cmp(&b.start));
+        ret.sort_by_key(|a| a.start);
         ret
     }
 }
@@ -92,6 +114,7 @@ mod tests {
         let rate = Rate {
             name: "test".to_owned(),
             unit_cost: 0.2,
+            export_price: 0.0,
             windows: vec![
                 RateWindow {
                     start: RateTime::from_str("09:00").unwrap(),
@@ -107,6 +130,7 @@ mod tests {
             charge: RateCharge {
                 mode: ChargeMode::Capacity(1.0),
                 unit_limit: 0,
+                strategy: ChargeStrategy::Immediate,
             },
             discharge: RateDischarge {
                 mode: DischargeMode::None,
@@ -115,16 +139,16 @@ mod tests {
             reserve: 0.0,
         };
 
-        let next = rate.schedule(Local.ymd(2022, 04, 18).and_hms(8, 0, 0).with_timezone(&Utc));
-        assert_eq!(Local.ymd(2022, 04, 18).and_hms(9, 0, 0), next[0].start);
-        assert_eq!(Local.ymd(2022, 04, 18).and_hms(11, 0, 0), next[1].start);
+        let next = rate.schedule(Local.with_ymd_and_hms(2022, 4, 18, 8, 0, 0).unwrap().with_timezone(&Utc));
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 18, 9, 0, 0).unwrap(), next[0].start);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 18, 11, 0, 0).unwrap(), next[1].start);
 
-        let next = rate.schedule(Local.ymd(2022, 04, 18).and_hms(9, 0, 0).with_timezone(&Utc));
-        assert_eq!(Local.ymd(2022, 04, 18).and_hms(9, 0, 0), next[0].start);
-        assert_eq!(Local.ymd(2022, 04, 18).and_hms(11, 0, 0), next[1].start);
+        let next = rate.schedule(Local.with_ymd_and_hms(2022, 4, 18, 9, 0, 0).unwrap().with_timezone(&Utc));
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 18, 9, 0, 0).unwrap(), next[0].start);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 18, 11, 0, 0).unwrap(), next[1].start);
 
-        let next = rate.schedule(Utc.ymd(2022, 04, 18).and_hms(10, 0, 0));
-        assert_eq!(Local.ymd(2022, 04, 18).and_hms(11, 0, 0), next[0].start);
-        assert_eq!(Local.ymd(2022, 04, 19).and_hms(9, 0, 0), next[1].start);
+        let next = rate.schedule(Utc.with_ymd_and_hms(2022, 4, 18, 10, 0, 0).unwrap());
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 18, 11, 0, 0).unwrap(), next[0].start);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 19, 9, 0, 0).unwrap(), next[1].start);
     }
 }
@@ -0,0 +1,437 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::smart_ess::rate::{ChargeMode, DischargeMode, Rate, RateCharge, RateDischarge};
+use crate::smart_ess::window::RateWindowAbsolute;
+use crate::smart_ess::Schedule;
+
+/// Time allowed for an HTTP price fetch, well below the control loop interval
+/// since the fetch runs between ticks
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug)]
+pub enum TariffError {
+    /// Price file or endpoint could not be read
+    Io(std::io::Error),
+
+    /// Endpoint did not answer in time
+    Timeout,
+
+    /// Only plain `http://host[:port]/path` urls are supported
+    InvalidUrl(String),
+
+    /// Endpoint answered with something other than 200 OK
+    Http(String),
+
+    /// JSON price list is not valid
+    Json(serde_json::Error),
+
+    /// CSV price list line is not valid
+    Csv { line: usize, text: String },
+}
+
+impl Display for TariffError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TariffError::Io(e) => write!(f, "Failed to read prices: {}", e),
+            TariffError::Timeout => write!(f, "Price endpoint timed out"),
+            TariffError::InvalidUrl(u) => write!(f, "Unsupported price url '{}'", u),
+            TariffError::Http(s) => write!(f, "Price endpoint returned '{}'", s),
+            TariffError::Json(e) => write!(f, "Invalid price list: {}", e),
+            TariffError::Csv { line, text } => write!(f, "Invalid price on line {}: '{}'", line, text),
+        }
+    }
+}
+
+impl std::error::Error for TariffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TariffError::Io(e) => Some(e),
+            TariffError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for TariffError {
+    fn from(e: std::io::Error) -> Self {
+        TariffError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for TariffError {
+    fn from(e: serde_json::Error) -> Self {
+        TariffError::Json(e)
+    }
+}
+
+/// Price for an absolute time slot, field aliases accept Octopus Agile results
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PriceSlot {
+    #[serde(alias = "valid_from")]
+    pub start: DateTime<Utc>,
+
+    #[serde(alias = "valid_to")]
+    pub end: DateTime<Utc>,
+
+    /// Fiat cost per 1 kW/h
+    #[serde(alias = "value_inc_vat")]
+    pub unit_cost: f32,
+
+    /// Fiat paid per 1 kW/h fed into the grid
+    #[serde(default)]
+    pub export_price: f32,
+}
+
+/// A bare list of slots or an Agile style `{"results": [..]}` object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PriceList {
+    Slots(Vec<PriceSlot>),
+    Results { results: Vec<PriceSlot> },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TariffSource {
+    /// Local file path
+    File(String),
+
+    /// Plain HTTP url, eg. `http://127.0.0.1:8080/prices`
+    Http(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum PriceFormat {
+    /// List of [PriceSlot] objects
+    #[default]
+    Json,
+
+    /// `start,end,unit_cost[,export_price]` lines with RFC 3339 times, a header line is skipped
+    Csv,
+}
+
+/// Prices published per time slot, classified into rates by thresholds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DynamicTariff {
+    pub source: TariffSource,
+
+    #[serde(default)]
+    pub format: PriceFormat,
+
+    /// Slots costing this or less become charge rates
+    pub charge_below: f32,
+
+    /// Slots costing this or more become discharge rates
+    pub discharge_above: f32,
+
+    /// Charging during cheap slots
+    pub charge: RateCharge,
+
+    /// Discharging during expensive slots
+    pub discharge: RateDischarge,
+
+    /// Discharging during all other slots
+    pub normal: RateDischarge,
+}
+
+impl DynamicTariff {
+    /// Read the current price list from the source
+    pub async fn fetch(&self) -> Result<Vec<PriceSlot>, TariffError> {
+        let body = match &self.source {
+            TariffSource::File(path) => tokio::fs::read_to_string(path).await?,
+            TariffSource::Http(url) => {
+                tokio::time::timeout(FETCH_TIMEOUT, http_get(url))
+                    .await
+                    .map_err(|_| TariffError::Timeout)??
+            }
+        };
+        let mut slots = match self.format {
+            PriceFormat::Json => parse_json(&body)?,
+            PriceFormat::Csv => parse_csv(&body)?,
+        };
+        slots.sort_by_key(|s| s.start);
+        Ok(slots)
+    }
+
+    /// Rate for a slot price
+    fn rate(&self, unit_cost: f32, export_price: f32) -> Rate {
+        let disabled = RateCharge {
+            mode: ChargeMode::Disabled,
+            ..self.charge
+        };
+        let (name, charge, discharge) = if unit_cost <= self.charge_below {
+            let discharge = RateDischarge {
+                mode: DischargeMode::None,
+                ..self.normal
+            };
+            ("Cheap", self.charge, discharge)
+        } else if unit_cost >= self.discharge_above {
+            ("Expensive", disabled, self.discharge)
+        } else {
+            ("Normal", disabled, self.normal)
+        };
+        Rate {
+            name: name.to_owned(),
+            unit_cost,
+            export_price,
+            windows: vec![],
+            discharge,
+            charge,
+            reserve: 0.0,
+        }
+    }
+
+    /// Schedules for all slots which haven't ended at `from`, one per slot
+    /// so each keeps its own price. Back to back slots of the same rate share
+    /// one usage window
+    pub fn schedule(&self, prices: &[PriceSlot], from: DateTime<Utc>) -> Vec<Schedule> {
+        let mut ret: Vec<Schedule> = prices
+            .iter()
+            .map(|slot| {
+                let window = RateWindowAbsolute {
+                    start: slot.start,
+                    end: slot.end,
+                };
+                Schedule {
+                    rate: self.rate(slot.unit_cost, slot.export_price),
+                    usage_window: window.clone(),
+                    window,
+                }
+            })
+            .collect();
+
+        let joined = |a: &Schedule, b: &Schedule| {
+            a.rate.name == b.rate.name && a.window.end == b.window.start
+        };
+        for i in 1..ret.len() {
+            if joined(&ret[i - 1], &ret[i]) {
+                ret[i].usage_window.start = ret[i - 1].usage_window.start;
+            }
+        }
+        for i in (1..ret.len()).rev() {
+            if joined(&ret[i - 1], &ret[i]) {
+                ret[i - 1].usage_window.end = ret[i].usage_window.end;
+            }
+        }
+
+        ret.retain(|s| s.window.end > from);
+        ret
+    }
+}
+
+fn parse_json(body: &str) -> Result<Vec<PriceSlot>, TariffError> {
+    Ok(match serde_json::from_str(body)? {
+        PriceList::Slots(s) => s,
+        PriceList::Results { results } => results,
+    })
+}
+
+fn parse_csv(body: &str) -> Result<Vec<PriceSlot>, TariffError> {
+    let mut ret = vec![];
+    for (i, text) in body.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = text.split(',').map(|f| f.trim()).collect();
+        let slot = match fields[..] {
+            [start, end, cost, ref export @ ..] if export.len() <= 1 => (|| {
+                Some(PriceSlot {
+                    start: DateTime::parse_from_rfc3339(start).ok()?.with_timezone(&Utc),
+                    end: DateTime::parse_from_rfc3339(end).ok()?.with_timezone(&Utc),
+                    unit_cost: cost.parse().ok()?,
+                    export_price: match export.first() {
+                        Some(e) => e.parse().ok()?,
+                        None => 0.0,
+                    },
+                })
+            })(),
+            _ => None,
+        };
+        match slot {
+            Some(s) => ret.push(s),
+            // a header starts with a column name instead of a time
+            None if i == 0 && DateTime::parse_from_rfc3339(fields[0]).is_err() => continue,
+            None => {
+                return Err(TariffError::Csv {
+                    line: i + 1,
+                    text: text.to_owned(),
+                })
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Minimal HTTP/1.0 GET for price endpoints on the local network
+async fn http_get(url: &str) -> Result<String, TariffError> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| TariffError::InvalidUrl(url.to_owned()))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(TariffError::InvalidUrl(url.to_owned()));
+    }
+    let addr = if host.contains(':') {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = TcpStream::connect(addr).await?;
+    let req = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(req.as_bytes()).await?;
+    let mut rsp = vec![];
+    stream.read_to_end(&mut rsp).await?;
+
+    let rsp = String::from_utf8_lossy(&rsp);
+    let (head, body) = rsp
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| TariffError::Http(rsp.to_string()))?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(TariffError::Http(status.to_owned()));
+    }
+    Ok(body.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tokio::net::TcpListener;
+
+    fn get_tariff(source: TariffSource, format: PriceFormat) -> DynamicTariff {
+        serde_json::from_value(serde_json::json!({
+            "source": source,
+            "format": format,
+            "charge_below": 0.1,
+            "discharge_above": 0.3,
+            "charge": {"mode": {"Capacity": 1.0}, "unit_limit": 0},
+            "discharge": {"mode": "Spread", "max_power": 2500.0},
+            "normal": {"mode": {"Capacity": 1.0}, "max_power": 1000.0}
+        }))
+        .unwrap()
+    }
+
+    fn slot(hour: u32, minute: u32, unit_cost: f32) -> PriceSlot {
+        let start = Utc.with_ymd_and_hms(2024, 5, 3, hour, minute, 0).unwrap();
+        PriceSlot {
+            start,
+            end: start + chrono::Duration::try_minutes(30).unwrap(),
+            unit_cost,
+            export_price: 0.0,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let slots = parse_json(
+            r#"[{"start": "2024-05-03T00:00:00Z", "end": "2024-05-03T00:30:00Z", "unit_cost": 0.12}]"#,
+        )
+        .unwrap();
+        assert_eq!(slots, vec![slot(0, 0, 0.12)]);
+
+        let agile = parse_json(
+            r#"{"count": 1, "results": [{"value_exc_vat": 0.114, "value_inc_vat": 0.12,
+                "valid_from": "2024-05-03T00:00:00Z", "valid_to": "2024-05-03T00:30:00Z"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(agile, slots);
+
+        let csv = parse_csv(
+            "start,end,unit_cost\n2024-05-03T00:00:00Z, 2024-05-03T00:30:00Z, 0.12\n\n\
+             2024-05-03T01:30:00+01:00,2024-05-03T02:00:00+01:00,0.2,0.15\n",
+        )
+        .unwrap();
+        let exported = PriceSlot {
+            export_price: 0.15,
+            ..slot(0, 30, 0.2)
+        };
+        assert_eq!(csv, vec![slot(0, 0, 0.12), exported]);
+
+        assert!(matches!(
+            parse_csv("2024-05-03T00:00:00Z,2024-05-03T00:30:00Z,0.12\nbad,line"),
+            Err(TariffError::Csv { line: 2, .. })
+        ));
+        // without a header the first row must be valid too
+        assert!(matches!(
+            parse_csv(
+                "2024-05-03T00:00:00Z,2024-05-03T00:30:00Z,cheap\n\
+                 2024-05-03T00:30:00Z,2024-05-03T01:00:00Z,0.2\n"
+            ),
+            Err(TariffError::Csv { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn schedule() {
+        let tariff = get_tariff(TariffSource::File("".to_owned()), PriceFormat::Json);
+        let prices = vec![
+            slot(0, 0, 0.05),
+            slot(0, 30, 0.07),
+            slot(1, 0, 0.2),
+            slot(1, 30, 0.35),
+            slot(2, 0, 0.08),
+        ];
+
+        let from = Utc.with_ymd_and_hms(2024, 5, 3, 0, 10, 0).unwrap();
+        let sch = tariff.schedule(&prices, from);
+        let names: Vec<&str> = sch.iter().map(|s| s.rate.name.as_str()).collect();
+        assert_eq!(names, vec!["Cheap", "Cheap", "Normal", "Expensive", "Cheap"]);
+
+        // adjacent slots of the same rate keep their own window and price
+        assert_eq!(sch[0].window.end, prices[0].end);
+        assert_eq!(sch[1].window.start, prices[1].start);
+        assert_eq!(sch[0].rate.unit_cost, 0.05);
+        assert_eq!(sch[1].rate.unit_cost, 0.07);
+        assert!(sch[0].rate.charge.charge_enabled());
+        assert_eq!(sch[0].rate.discharge.mode, DischargeMode::None);
+        assert!(!sch[2].rate.charge.charge_enabled());
+        assert_eq!(sch[3].rate.discharge.mode, DischargeMode::Spread);
+
+        let later = Utc.with_ymd_and_hms(2024, 5, 3, 1, 45, 0).unwrap();
+        assert_eq!(tariff.schedule(&prices, later).len(), 2, "ended slots are skipped");
+    }
+
+    #[tokio::test]
+    async fn fetch_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/prices.csv", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for status in ["200 OK", "404 Not Found"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut req = [0u8; 1024];
+                let n = stream.read(&mut req).await.unwrap();
+                assert!(req[..n].starts_with(b"GET /prices.csv HTTP/1.0\r\n"));
+                let rsp = format!(
+                    "HTTP/1.0 {}\r\nContent-Type: text/csv\r\n\r\n\
+                     2024-05-03T00:30:00Z,2024-05-03T01:00:00Z,0.2\n\
+                     2024-05-03T00:00:00Z,2024-05-03T00:30:00Z,0.12\n",
+                    status
+                );
+                stream.write_all(rsp.as_bytes()).await.unwrap();
+            }
+        });
+
+        let tariff = get_tariff(TariffSource::Http(url), PriceFormat::Csv);
+        let slots = tariff.fetch().await.unwrap();
+        assert_eq!(slots, vec![slot(0, 0, 0.12), slot(0, 30, 0.2)], "sorted by start");
+
+        assert!(matches!(tariff.fetch().await, Err(TariffError::Http(s)) if s == "HTTP/1.0 404 Not Found"));
+
+        let bad = get_tariff(TariffSource::Http("https://example.com".to_owned()), PriceFormat::Json);
+        assert!(matches!(bad.fetch().await, Err(TariffError::InvalidUrl(_))));
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::smart_ess::{ControllerError, Schedule};
+
+/// Grid energy used for charging in the current rate window, saved to disk
+/// so [crate::smart_ess::rate::RateCharge::unit_limit] survives restarts
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UnitUsage {
+    window: Option<WindowUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct WindowUsage {
+    rate: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+
+    /// Units used in kWh
+    units: f32,
+
+    /// Time of the last sample
+    last: DateTime<Utc>,
+}
+
+/// Longest gap between samples which is still counted, covers restarts
+fn max_gap() -> Duration {
+    Duration::try_minutes(5).unwrap()
+}
+
+impl UnitUsage {
+    /// Load saved usage, a missing file starts empty
+    pub fn load(path: &str) -> Result<UnitUsage, ControllerError> {
+        let mut json = String::new();
+        match File::open(path) {
+            Ok(mut f) => f.read_to_string(&mut json).map_err(ControllerError::Usage)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(UnitUsage::default()),
+            Err(e) => return Err(ControllerError::Usage(e)),
+        };
+        serde_json::from_str(&json).map_err(|e| ControllerError::Usage(e.into()))
+    }
+
+    /// Save to a temporary file next to `path` and rename it into place, so
+    /// an interrupted save never leaves a truncated file behind
+    pub fn save(&self, path: &str) -> Result<(), ControllerError> {
+        let json = serde_json::to_string(self)?;
+        let tmp = format!("{}.tmp", path);
+        File::create(&tmp)
+            .and_then(|mut f| {
+                f.write_all(json.as_bytes())?;
+                f.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(ControllerError::Usage)
+    }
+
+    /// Units used in the window containing `now`
+    pub fn units(&self, now: DateTime<Utc>) -> f32 {
+        match &self.window {
+            Some(w) if w.start <= now && now <= w.end => w.units,
+            _ => 0.0,
+        }
+    }
+
+    /// Add `watts` of charging since the last sample to the schedule's usage
+    /// window, starting over when the window changes. Returns true if a new
+    /// window was started
+    pub fn record(&mut self, sch: &Schedule, now: DateTime<Utc>, watts: f32) -> bool {
+        let window = &sch.usage_window;
+        let same = matches!(&self.window, Some(w) if w.rate == sch.rate.name && w.start == window.start);
+        if !same {
+            self.window = Some(WindowUsage {
+                rate: sch.rate.name.clone(),
+                start: window.start,
+                end: window.end,
+                units: 0.0,
+                last: now,
+            });
+            return true;
+        }
+
+        let w = self.window.as_mut().unwrap();
+        // a run of price slots grows when more prices are published
+        w.end = window.end;
+        let dt = now - w.last;
+        if dt > Duration::zero() && dt <= max_gap() {
+            w.units += watts.max(0.0) * dt.num_milliseconds() as f32 / 3_600_000_000.0;
+        }
+        w.last = now;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smart_ess::rate::{
+        ChargeMode, ChargeStrategy, DischargeMode, Rate, RateCharge, RateDischarge,
+    };
+    use crate::smart_ess::window::RateWindowAbsolute;
+    use chrono::TimeZone;
+
+    fn schedule(start_hour: u32) -> Schedule {
+        let window = RateWindowAbsolute {
+            start: Utc.with_ymd_and_hms(2022, 5, 3, start_hour, 0, 0).unwrap(),
+            end: Utc.with_ymd_and_hms(2022, 5, 3, start_hour + 2, 0, 0).unwrap(),
+        };
+        Schedule {
+            rate: Rate {
+                name: "Night".to_owned(),
+                unit_cost: 0.0,
+                export_price: 0.0,
+                windows: vec![],
+                discharge: RateDischarge {
+                    mode: DischargeMode::None,
+                    max_power: 0.0,
+                },
+                charge: RateCharge {
+                    mode: ChargeMode::Capacity(1.0),
+                    unit_limit: 5,
+                    strategy: ChargeStrategy::Immediate,
+                },
+                reserve: 0.0,
+            },
+            usage_window: window.clone(),
+            window,
+        }
+    }
+
+    #[test]
+    fn record() {
+        let sch = schedule(1);
+        let t = sch.window.start;
+        let mut usage = UnitUsage::default();
+
+        assert!(usage.record(&sch, t, 3000.0));
+        assert_eq!(usage.units(t), 0.0, "first sample starts the window");
+
+        assert!(!usage.record(&sch, t + Duration::try_minutes(2).unwrap(), 3000.0));
+        usage.record(&sch, t + Duration::try_minutes(4).unwrap(), -500.0);
+        assert!((usage.units(t) - 0.1).abs() < 0.0001, "{:?}", usage);
+
+        // gaps longer than a few minutes are not counted
+        usage.record(&sch, t + Duration::try_minutes(60).unwrap(), 3000.0);
+        assert!((usage.units(t) - 0.1).abs() < 0.0001, "{:?}", usage);
+
+        let after = sch.window.end + Duration::try_minutes(1).unwrap();
+        assert_eq!(usage.units(after), 0.0, "outside the window");
+
+        let next = schedule(4);
+        assert!(usage.record(&next, next.window.start, 3000.0));
+        assert_eq!(usage.units(next.window.start), 0.0, "new window");
+    }
+
+    #[test]
+    fn save_load() {
+        let path = std::env::temp_dir().join(format!("ve_smart_ess_usage_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        assert_eq!(UnitUsage::load(path).unwrap(), UnitUsage::default());
+
+        let sch = schedule(1);
+        let mut usage = UnitUsage::default();
+        usage.record(&sch, sch.window.start, 0.0);
+        usage.record(&sch, sch.window.start + Duration::try_minutes(5).unwrap(), 12_000.0);
+        usage.save(path).unwrap();
+
+        let loaded = UnitUsage::load(path).unwrap();
+        assert_eq!(loaded, usage);
+        assert!((loaded.units(sch.window.start) - 1.0).abs() < 0.0001);
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+
+        std::fs::write(path, "{\"window\":").unwrap();
+        let loaded = UnitUsage::load(path);
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(loaded, Err(ControllerError::Usage(_))));
+    }
+}
@@ -1,6 +1,8 @@
-use chrono::{DateTime, Datelike, Local, Timelike, Utc, Duration};
+use chrono::{DateTime, Datelike, Local, TimeZone, Timelike, Utc, Duration};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::num::ParseIntError;
 use std::ops::{Sub};
 use std::str::FromStr;
 
@@ -50,12 +52,42 @@ impl From<chrono::Weekday> for Weekday {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct RateError(pub String);
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateError {
+    /// Hour or minute out of range
+    InvalidTime { hour: u8, minute: u8 },
+
+    /// Time is not in `HH:MM` format
+    InvalidFormat(String),
+
+    /// Hour or minute is not a number
+    Parse(ParseIntError),
+}
 
-impl<ToStr: ToString> From<ToStr> for RateError {
-    fn from(s: ToStr) -> Self {
-        RateError(s.to_string())
+impl Display for RateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateError::InvalidTime { hour, minute } => {
+                write!(f, "Invalid time range {:02}:{:02}", hour, minute)
+            }
+            RateError::InvalidFormat(s) => write!(f, "Invalid time format '{}'", s),
+            RateError::Parse(e) => write!(f, "Invalid time: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RateError::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseIntError> for RateError {
+    fn from(e: ParseIntError) -> Self {
+        RateError::Parse(e)
     }
 }
 
@@ -72,7 +104,7 @@ impl RateWindow {
         days.sort();
 
         // we pick the day before `from` to catch rates which cross days
-        let from_sch = from.sub(Duration::days(1));
+        let from_sch = from.sub(Duration::try_days(1).unwrap());
         let from_weekday: Weekday = from_sch.weekday().into();
         let wrap = days.iter().filter(|d| **d < from_weekday);
 
@@ -81,21 +113,25 @@ impl RateWindow {
             .filter(|d| **d >= from_weekday)
             .chain(wrap)
             .map(|wd| {
-                let days = Weekday::days_from(&from_weekday, &wd);
-                let start_local = from_sch.date().with_timezone(&Local).and_hms(
-                    self.start.hour as u32,
-                    self.start.minute as u32,
-                    0,
-                ) + Duration::days(days as i64);
+                let days = Weekday::days_from(&from_weekday, wd);
+                let start_local = Local
+                    .from_local_datetime(
+                        &from_sch
+                            .date_naive()
+                            .and_hms_opt(self.start.hour as u32, self.start.minute as u32, 0)
+                            .unwrap(),
+                    )
+                    .unwrap()
+                    + Duration::try_days(days as i64).unwrap();
                 let start_utc = start_local.with_timezone(&Utc);
                 RateWindowAbsolute {
                     start: start_utc,
-                    end: start_utc + Duration::minutes(self.period() as i64),
+                    end: start_utc + Duration::try_minutes(self.period() as i64).unwrap(),
                 }
             })
             .filter(|d| d.start >= from || d.is_inside(from))
             .collect();
-        ret.sort_by(|a, b| a.start.cmp(&b.start));
+        ret.sort_by_key(|a| a.start);
         ret
     }
 
@@ -133,7 +169,7 @@ pub struct RateTime {
 impl RateTime {
     pub fn new(hour: u8, minute: u8) -> Result<Self, RateError> {
         if hour > 23 || minute > 59 {
-            return Err(RateError("Invalid time range".to_owned()));
+            return Err(RateError::InvalidTime { hour, minute });
         }
 
         Ok(RateTime { hour, minute })
@@ -148,8 +184,10 @@ impl FromStr for RateTime {
     type Err = RateError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let ss: Vec<&str> = s.split(":").collect();
-        RateTime::new(u8::from_str(ss[0])?, u8::from_str(ss[1])?)
+        let (hour, minute) = s
+            .split_once(':')
+            .ok_or_else(|| RateError::InvalidFormat(s.to_owned()))?;
+        RateTime::new(u8::from_str(hour)?, u8::from_str(minute)?)
     }
 }
 
@@ -172,7 +210,7 @@ impl PartialEq for RateTime {
 
 impl PartialOrd for RateTime {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.minute_of_day().partial_cmp(&other.minute_of_day())
+        Some(self.cmp(other))
     }
 }
 
@@ -197,8 +235,21 @@ mod tests {
         let test_nine_am = RateTime::from_str("09:59").unwrap();
         assert_eq!(&nine_am, &test_nine_am);
 
-        assert_eq!(RateTime::from_str("24:00").is_err(), true);
-        assert_eq!(RateTime::from_str("00:60").is_err(), true);
+        assert!(RateTime::from_str("24:00").is_err());
+        assert!(RateTime::from_str("00:60").is_err());
+    }
+
+    #[test]
+    fn rate_time_errors() {
+        assert_eq!(
+            RateTime::from_str("24:00"),
+            Err(RateError::InvalidTime { hour: 24, minute: 0 })
+        );
+        assert_eq!(
+            RateTime::from_str("24"),
+            Err(RateError::InvalidFormat("24".to_owned()))
+        );
+        assert!(matches!(RateTime::from_str("a:1"), Err(RateError::Parse(_))));
     }
 
     #[test]
@@ -211,10 +262,10 @@ mod tests {
 
     #[test]
     fn rate_next_from() {
-        let a_monday = Local.ymd(2022, 04, 18).and_hms(8, 0, 0);
-        let a_saturday = Local.ymd(2022, 04, 16).and_hms(8, 0, 0);
-        let a_friday = Local.ymd(2022, 04, 22).and_hms(8, 59, 59);
-        let a_sunday_inside = Local.ymd(2022, 04, 24).and_hms(16, 0, 0);
+        let a_monday = Local.with_ymd_and_hms(2022, 4, 18, 8, 0, 0).unwrap();
+        let a_saturday = Local.with_ymd_and_hms(2022, 4, 16, 8, 0, 0).unwrap();
+        let a_friday = Local.with_ymd_and_hms(2022, 4, 22, 8, 59, 59).unwrap();
+        let a_sunday_inside = Local.with_ymd_and_hms(2022, 4, 24, 16, 0, 0).unwrap();
 
         let rate = RateWindow {
             start: RateTime::from_str("09:00").unwrap(),
@@ -224,20 +275,20 @@ mod tests {
 
         let sch = rate.schedule(a_monday.with_timezone(&Utc));
         let next = sch.first().unwrap();
-        assert_eq!(Local.ymd(2022, 04, 22).and_hms(9, 0, 0), next.start);
-        assert_eq!(Local.ymd(2022, 04, 22).and_hms(16, 59, 0), next.end);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 22, 9, 0, 0).unwrap(), next.start);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 22, 16, 59, 0).unwrap(), next.end);
 
         let sch = rate.schedule(a_saturday.with_timezone(&Utc));
         let next = sch.first().unwrap();
-        assert_eq!(Local.ymd(2022, 04, 17).and_hms(9, 0, 0), next.start);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 17, 9, 0, 0).unwrap(), next.start);
 
         let sch = rate.schedule(a_friday.with_timezone(&Utc));
         let next = sch.first().unwrap();
-        assert_eq!(Local.ymd(2022, 04, 22).and_hms(9, 0, 0), next.start, "same day before schedule");
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 22, 9, 0, 0).unwrap(), next.start, "same day before schedule");
 
         let sch = rate.schedule(a_sunday_inside.with_timezone(&Utc));
         let next = sch.first().unwrap();
-        assert_eq!(Local.ymd(2022, 04, 24).and_hms(9, 0, 0), next.start);
+        assert_eq!(Local.with_ymd_and_hms(2022, 4, 24, 9, 0, 0).unwrap(), next.start);
     }
 
     #[test]
@@ -284,11 +335,11 @@ mod tests {
             days: ALL_WEEKDAYS.into(),
         };
 
-        let from = Local.ymd(2022, 05, 03).and_hms(2, 0, 0).with_timezone(&Utc);
+        let from = Local.with_ymd_and_hms(2022, 5, 3, 2, 0, 0).unwrap().with_timezone(&Utc);
         let sch = rate.schedule(from);
         let next = sch.first().unwrap();
 
-        assert_eq!(next.start, Local.ymd(2022, 05, 02).and_hms(23, 0, 0));
+        assert_eq!(next.start, Local.with_ymd_and_hms(2022, 5, 2, 23, 0, 0).unwrap());
     }
 
     #[test]
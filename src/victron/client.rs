@@ -1,58 +1,520 @@
+use std::fmt::{Display, Formatter};
 use std::io::Error;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use tokio::sync::Mutex;
+use tokio::time::error::Elapsed;
 use tokio_modbus::client::{Context, Reader, tcp, Writer};
 use tokio_modbus::slave::{Slave, SlaveContext};
 
 use crate::victron::VictronError;
 
+/// Max number of registers the GX will return in a single read
+const MAX_READ_COUNT: u16 = 125;
+
+/// Default time to wait for a response before giving up on the connection
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How to reach the Modbus server
+#[derive(Debug, Clone)]
+pub enum Transport {
+    /// Modbus TCP, usually a GX device
+    Tcp(SocketAddr),
+
+    /// Modbus RTU over a serial line (RS485)
+    #[cfg(feature = "rtu")]
+    Rtu { path: String, baud_rate: u32 },
+}
+
+/// Modbus connection to a GX device shared by all device wrappers,
+/// requests from each wrapper are serialized over the same socket
+#[derive(Clone)]
+pub struct VictronConnection {
+    inner: Arc<Mutex<Connection>>,
+}
+
+struct Connection {
+    transport: Transport,
+    client: Option<Context>,
+    state: ConnectionState,
+    backoff: Backoff,
+
+    /// Max time to wait for a connect or for each request
+    timeout: Duration,
+}
+
+/// Requests for a single unit id over a shared [VictronConnection]
 pub(crate) struct VictronClient {
-    client: Context,
+    conn: VictronConnection,
+    unit: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+
+    /// Connection was lost, next attempt will be made after the backoff delay
+    Reconnecting { attempt: u32 },
+}
+
+/// Exponential backoff with jitter between reconnect attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay before the first reconnect attempt
+    pub initial: Duration,
+
+    /// Upper bound for the delay between attempts
+    pub max: Duration,
+
+    /// Fraction of the delay to randomize (0.0 - 1.0)
+    pub jitter: f32,
+
+    attempt: u32,
+    next_attempt: Option<Instant>,
+}
+
+/// A contiguous range of registers fetched in a single read,
+/// values are decoded by absolute register address
+#[derive(Debug, Clone)]
+pub struct RegisterBlock {
+    start: u16,
+    words: Vec<u16>,
 }
 
 impl From<std::io::Error> for VictronError {
     fn from(e: Error) -> Self {
-        Self(e.to_string())
+        Self::Transport(e)
     }
 }
 
-impl VictronClient {
-    pub async fn new(addr: SocketAddr) -> Result<Self, VictronError> {
-        let ctx = tcp::connect(addr).await?;
-        Ok(Self { client: ctx })
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter: 0.25,
+            attempt: 0,
+            next_attempt: None,
+        }
     }
+}
 
-    pub fn set_unit(&mut self, unit: u8) {
-        self.client.set_slave(Slave(unit))
+impl Backoff {
+    /// Delay before attempt number `attempt` (starting at 1), without jitter
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(16);
+        self.initial.saturating_mul(1 << exp).min(self.max)
     }
 
-    pub async fn write_i16(&mut self, addr: u16, value: i16) -> Result<(), VictronError> {
-        Ok(self.write_u16(addr, value as u16).await?)
+    /// Delay before attempt number `attempt` with up to `jitter` of it taken off
+    pub fn delay_with_jitter(&self, attempt: u32) -> Duration {
+        let delay = self.delay(attempt);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let r = (nanos % 1000) as f32 / 1000.0;
+        delay.mul_f32(1.0 - self.jitter.clamp(0.0, 1.0) * r)
     }
 
-    pub async fn write_u16(&mut self, addr: u16, value: u16) -> Result<(), VictronError> {
-        Ok(self.client.write_single_register(addr, value).await
-            .map_err(|e| VictronError(e.to_string()))?.map_err(|e| VictronError(e.to_string()))?)
+    fn ready(&self) -> bool {
+        self.next_attempt.map(|t| Instant::now() >= t).unwrap_or(true)
+    }
+
+    fn failed(&mut self) {
+        self.attempt += 1;
+        self.next_attempt = Some(Instant::now() + self.delay_with_jitter(self.attempt));
     }
 
-    pub async fn read_bool(&mut self, addr: u16) -> Result<bool, VictronError> {
-        Ok(match dbg!(self.read_u16(addr).await?) {
-            0 => false,
-            1 => true,
-            _ => return Err(VictronError("Unknown bool state!".to_owned())),
+    fn reset(&mut self) {
+        self.attempt = 0;
+        self.next_attempt = None;
+    }
+}
+
+impl RegisterBlock {
+    pub fn new(start: u16, words: Vec<u16>) -> Self {
+        Self { start, words }
+    }
+
+    /// First register address in this block
+    pub fn start(&self) -> u16 {
+        self.start
+    }
+
+    /// Last register address in this block, `None` when the block is empty
+    pub fn end(&self) -> Option<u16> {
+        let len = self.words.len() as u32;
+        if len == 0 {
+            return None;
+        }
+        u16::try_from(self.start as u32 + len - 1).ok()
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        addr >= self.start && ((addr - self.start) as usize) < self.words.len()
+    }
+
+    pub fn u16(&self, addr: u16) -> Result<u16, VictronError> {
+        if !self.contains(addr) {
+            return Err(VictronError::NotInBlock {
+                addr,
+                start: self.start,
+                end: self.end(),
+            });
+        }
+        Ok(self.words[(addr - self.start) as usize])
+    }
+
+    pub fn i16(&self, addr: u16) -> Result<i16, VictronError> {
+        Ok(self.u16(addr)? as i16)
+    }
+
+    pub fn bool(&self, addr: u16) -> Result<bool, VictronError> {
+        match self.u16(addr)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(VictronError::UnknownValue { what: "bool", value }),
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(feature = "rtu")]
+            Transport::Rtu { path, baud_rate } => write!(f, "{}@{}", path, baud_rate),
+        }
+    }
+}
+
+impl Transport {
+    async fn connect(&self, timeout: Duration) -> Result<Context, VictronError> {
+        tokio::time::timeout(timeout, self.connect_inner())
+            .await
+            .map_err(|_| VictronError::Timeout(timeout))?
+            .map_err(VictronError::Transport)
+    }
+
+    async fn connect_inner(&self) -> Result<Context, Error> {
+        match self {
+            Transport::Tcp(addr) => tcp::connect(*addr).await,
+            #[cfg(feature = "rtu")]
+            Transport::Rtu { path, baud_rate } => {
+                let serial = tokio_serial::SerialStream::open(&tokio_serial::new(path, *baud_rate))?;
+                Ok(tokio_modbus::client::rtu::attach(serial))
+            }
+        }
+    }
+}
+
+impl VictronConnection {
+    /// Connect to a Modbus TCP server, the connection can be shared by
+    /// cloning the handle or creating device wrappers from it
+    pub async fn connect(addr: SocketAddr) -> Result<Self, VictronError> {
+        Self::open(Transport::Tcp(addr)).await
+    }
+
+    pub async fn open(transport: Transport) -> Result<Self, VictronError> {
+        Self::open_with_timeout(transport, DEFAULT_TIMEOUT).await
+    }
+
+    /// Open a connection waiting at most `timeout` for the connect and each request
+    pub async fn open_with_timeout(transport: Transport, timeout: Duration) -> Result<Self, VictronError> {
+        let ctx = transport.connect(timeout).await?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Connection {
+                transport,
+                client: Some(ctx),
+                state: ConnectionState::Connected,
+                backoff: Backoff::default(),
+                timeout,
+            })),
         })
     }
 
-    pub async fn read_i16(&mut self, addr: u16) -> Result<i16, VictronError> {
-        Ok(self.read_u16(addr).await? as i16)
+    pub async fn state(&self) -> ConnectionState {
+        self.inner.lock().await.state
+    }
+
+    /// Set the time to wait for each request, a request which times out
+    /// drops the connection as the socket is likely half-open
+    pub async fn set_timeout(&self, timeout: Duration) {
+        self.inner.lock().await.timeout = timeout;
+    }
+}
+
+impl Connection {
+    /// Get the connected context, reconnecting first if the connection
+    /// was lost and the backoff delay has passed
+    async fn context(&mut self, unit: u8) -> Result<&mut Context, VictronError> {
+        if self.client.is_none() {
+            if !self.backoff.ready() {
+                return Err(VictronError::NotConnected);
+            }
+            match self.transport.connect(self.timeout).await {
+                Ok(ctx) => {
+                    self.client = Some(ctx);
+                    self.state = ConnectionState::Connected;
+                    self.backoff.reset();
+                }
+                Err(e) => {
+                    self.backoff.failed();
+                    self.state = ConnectionState::Reconnecting {
+                        attempt: self.backoff.attempt,
+                    };
+                    return Err(e);
+                }
+            }
+        }
+        let ctx = self.client.as_mut().unwrap();
+        ctx.set_slave(Slave(unit));
+        Ok(ctx)
+    }
+
+    /// Drop the connection after a transport error so the next call reconnects
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.backoff.reset();
+        self.state = ConnectionState::Reconnecting { attempt: 0 };
+    }
+
+    /// Map the result of a timed request, dropping the connection on
+    /// transport errors and timeouts
+    fn check<T>(
+        &mut self,
+        r: Result<tokio_modbus::Result<T>, Elapsed>,
+    ) -> Result<T, VictronError> {
+        match r {
+            Ok(Ok(Ok(v))) => Ok(v),
+            Ok(Ok(Err(e))) => Err(VictronError::Exception(e)),
+            Ok(Err(e)) => {
+                self.disconnect();
+                Err(VictronError::Transport(e))
+            }
+            Err(_) => {
+                self.disconnect();
+                Err(VictronError::Timeout(self.timeout))
+            }
+        }
+    }
+}
+
+impl VictronClient {
+    pub fn new(conn: &VictronConnection, unit: u8) -> Self {
+        Self {
+            conn: conn.clone(),
+            unit,
+        }
+    }
+
+    pub async fn state(&self) -> ConnectionState {
+        self.conn.state().await
+    }
+
+    pub async fn write_u16(&mut self, addr: u16, value: u16) -> Result<(), VictronError> {
+        let mut conn = self.conn.inner.lock().await;
+        let timeout = conn.timeout;
+        let ctx = conn.context(self.unit).await?;
+        let r = tokio::time::timeout(timeout, ctx.write_single_register(addr, value)).await;
+        conn.check(r)
     }
 
     pub async fn read_u16(&mut self, addr: u16) -> Result<u16, VictronError> {
-        let v = self
-            .client
-            .read_input_registers(addr, 1)
-            .await
-            .map_err(|e| VictronError(e.to_string()))?.map_err(|e| VictronError(e.to_string()))?;
-        Ok(v[0] as u16)
+        self.read_block(addr, 1).await?.u16(addr)
+    }
+
+    /// Read `count` registers starting at `addr`, split into as few requests as possible
+    pub async fn read_block(&mut self, addr: u16, count: u16) -> Result<RegisterBlock, VictronError> {
+        if count == 0 || addr as u32 + count as u32 > u16::MAX as u32 + 1 {
+            return Err(VictronError::OutOfRange {
+                what: "register block",
+                value: count as u32,
+            });
+        }
+        let mut conn = self.conn.inner.lock().await;
+        let mut words = Vec::with_capacity(count as usize);
+        while words.len() < count as usize {
+            let offset = words.len() as u16;
+            let n = (count - offset).min(MAX_READ_COUNT);
+            let timeout = conn.timeout;
+            let ctx = conn.context(self.unit).await?;
+            let r = tokio::time::timeout(timeout, ctx.read_input_registers(addr + offset, n)).await;
+            let v = conn.check(r)?;
+            if v.len() != n as usize {
+                return Err(VictronError::ShortRead {
+                    expected: n,
+                    got: v.len() as u16,
+                });
+            }
+            words.extend(v);
+        }
+        Ok(RegisterBlock::new(addr, words))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_delay() {
+        let backoff = Backoff::default();
+
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(4), Duration::from_secs(8));
+        assert_eq!(backoff.delay(100), backoff.max);
+
+        for attempt in 1..10 {
+            let d = backoff.delay_with_jitter(attempt);
+            assert!(d <= backoff.delay(attempt));
+            assert!(d >= backoff.delay(attempt).mul_f32(1.0 - backoff.jitter));
+        }
+    }
+
+    #[test]
+    fn block_decode() {
+        let block = RegisterBlock::new(37, vec![50, 1, 0, 0xfc18, 2]);
+
+        assert_eq!(block.start(), 37);
+        assert_eq!(block.end(), Some(41));
+        assert_eq!(block.u16(37).unwrap(), 50);
+        assert!(block.bool(38).unwrap());
+        assert!(!block.bool(39).unwrap());
+        assert_eq!(block.i16(40).unwrap(), -1000);
+        assert!(block.bool(41).is_err());
+        assert!(block.u16(36).is_err());
+        assert!(block.u16(42).is_err());
+
+        let empty = RegisterBlock::new(0, vec![]);
+        assert_eq!(empty.end(), None);
+        assert!(!empty.contains(0));
+        assert!(empty.u16(0).is_err());
+
+        let last = RegisterBlock::new(u16::MAX - 1, vec![1, 2]);
+        assert_eq!(last.end(), Some(u16::MAX));
+        assert_eq!(last.u16(u16::MAX).unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn exceptions() {
+        use crate::victron::sim::{Simulator, INVERTER};
+        use crate::victron::Exception;
+
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+
+        let mut cli = VictronClient::new(&conn, INVERTER);
+        assert!(matches!(
+            cli.read_u16(1000).await,
+            Err(VictronError::Exception(Exception::IllegalDataAddress))
+        ));
+        assert!(matches!(
+            cli.write_u16(30, 1).await,
+            Err(VictronError::Exception(Exception::IllegalDataAddress))
+        ));
+
+        let mut missing = VictronClient::new(&conn, 1);
+        let e = missing.read_u16(3).await.unwrap_err();
+        assert!(matches!(e, VictronError::Exception(Exception::GatewayTargetDevice)));
+        assert!(e.is_transient());
+
+        // exceptions don't drop the connection
+        assert_eq!(conn.state().await, ConnectionState::Connected);
+        assert!(cli.read_u16(3).await.is_ok());
+
+        // empty and wrapping ranges are rejected before reaching the device
+        assert!(matches!(
+            cli.read_block(0, 0).await,
+            Err(VictronError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            cli.read_block(u16::MAX, 2).await,
+            Err(VictronError::OutOfRange { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = tokio::spawn(async move {
+            // hold the socket open without ever answering
+            let (sock, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            drop(sock);
+        });
+
+        let conn = VictronConnection::connect(addr).await.unwrap();
+        conn.set_timeout(Duration::from_millis(100)).await;
+
+        let mut cli = VictronClient::new(&conn, 227);
+        assert!(matches!(
+            cli.read_u16(3).await,
+            Err(VictronError::Timeout(d)) if d == Duration::from_millis(100)
+        ));
+        assert_eq!(conn.state().await, ConnectionState::Reconnecting { attempt: 0 });
+        accept.abort();
+    }
+
+    #[cfg(feature = "rtu")]
+    #[tokio::test]
+    async fn rtu_pty() {
+        use std::future::{ready, Ready};
+        use std::sync::Mutex;
+        use tokio_modbus::prelude::{Exception, Request, Response, SlaveRequest};
+        use tokio_serial::{SerialPort, SerialStream};
+
+        struct Registers(Mutex<Vec<u16>>);
+
+        impl tokio_modbus::server::Service for Registers {
+            type Request = SlaveRequest<'static>;
+            type Future = Ready<Result<Response, Exception>>;
+
+            fn call(&self, req: Self::Request) -> Self::Future {
+                if req.slave != 227 {
+                    return ready(Err(Exception::GatewayTargetDevice));
+                }
+                let mut regs = self.0.lock().unwrap();
+                ready(match req.request {
+                    Request::ReadInputRegisters(addr, cnt) => Ok(Response::ReadInputRegisters(
+                        regs[addr as usize..(addr + cnt) as usize].to_vec(),
+                    )),
+                    Request::WriteSingleRegister(addr, v) => {
+                        regs[addr as usize] = v;
+                        Ok(Response::WriteSingleRegister(addr, v))
+                    }
+                    _ => Err(Exception::IllegalFunction),
+                })
+            }
+        }
+
+        // keep the slave end open so the master doesn't see a hangup
+        let (master, slave) = SerialStream::pair().unwrap();
+        let path = slave.name().unwrap();
+        let server = tokio_modbus::server::rtu::Server::new(master);
+        tokio::spawn(server.serve_forever(Registers(Mutex::new(vec![0; 64]))));
+
+        let conn = VictronConnection::open(Transport::Rtu {
+            path,
+            baud_rate: 19200,
+        })
+        .await
+        .unwrap();
+        let mut cli = VictronClient::new(&conn, 227);
+        cli.write_u16(37, -1000i16 as u16).await.unwrap();
+        cli.write_u16(38, 1).await.unwrap();
+
+        let block = cli.read_block(37, 2).await.unwrap();
+        assert_eq!(block.i16(37).unwrap(), -1000);
+        assert!(block.bool(38).unwrap());
+
+        let mut other = VictronClient::new(&conn, 100);
+        assert!(other.read_u16(37).await.is_err());
+        drop(slave);
     }
 }
@@ -1,13 +1,42 @@
-use crate::victron::client::VictronClient;
-use crate::victron::Line;
-use crate::VictronError;
-use std::net::SocketAddr;
+use crate::victron::client::{ConnectionState, VictronClient, VictronConnection};
+use crate::victron::{Line, VictronError};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
 
 pub struct VictronESS {
     client: VictronClient,
+
+    /// com.victronenergy.settings, normally unit 100
+    settings: VictronClient,
+
+    /// Last value written to each register address and when it was written
+    /// by [VictronESS::update_param]
+    shadow: BTreeMap<u16, (Register, Option<DateTime<Utc>>)>,
+
+    /// Unchanged values are written again once their last write is older
+    /// than this, the Multi leaves external control when the setpoint is not
+    /// written for 60 seconds
+    max_age: Duration,
 }
 
+/// Register which no longer holds the value we last wrote
 #[derive(Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub expected: Register,
+
+    /// Raw register value read back
+    pub actual: u16,
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} changed to {}", self.expected, self.actual)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Hub4Mode {
     WithPhaseCompensation = 1,
     WithoutPhaseCompensation = 2,
@@ -22,76 +51,241 @@ impl TryFrom<u16> for Hub4Mode {
             1 => Hub4Mode::WithPhaseCompensation,
             2 => Hub4Mode::WithoutPhaseCompensation,
             3 => Hub4Mode::External,
-            e => return Err(VictronError(format!("Invalid Hub4 mode {}!", e))),
+            value => return Err(VictronError::UnknownValue { what: "hub4 mode", value }),
         })
     }
 }
 
-impl ToString for Hub4Mode {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for Hub4Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
             Hub4Mode::WithPhaseCompensation => "ESS with phase compensation",
             Hub4Mode::WithoutPhaseCompensation => "ESS without phase compensation",
             Hub4Mode::External => "Disabled / External Control",
-        }
-        .to_owned()
+        };
+        write!(f, "{}", str)
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Register {
     /// Value in watts.
     /// Positive values take power from grid.
     /// Negative values feed into the grid.
     PowerSetPoint(Line, i16),
 
-    /// Control charger
+    /// Control charger, written as 1 to disable and 0 to allow charging
     DisableCharge(bool),
 
-    /// Control feed-in from battery
+    /// Control feed-in from battery, written as 1 to disable and 0 to allow feed-in
     DisableFeedIn(bool),
 
     /// ESS Mode
     Mode(Hub4Mode),
+
+    /// Limit on power fed into the grid, in steps of 100 W
+    MaxFeedInPower(PowerLimit),
+
+    /// DVCC limit on the total battery charge current, in amps
+    MaxChargeCurrent(CurrentLimit),
+
+    /// Limit on inverter power drawn from the battery, in steps of 10 W
+    MaxDischargePower(PowerLimit),
+}
+
+impl Register {
+    /// Raw register value, fails if the value can't be represented
+    fn encode(&self) -> Result<u16, VictronError> {
+        Ok(match *self {
+            Register::PowerSetPoint(_, power) => power as u16,
+            Register::DisableCharge(v) => encode_bool(v),
+            Register::DisableFeedIn(v) => encode_bool(v),
+            Register::Mode(v) => v as u16,
+            Register::MaxFeedInPower(v) => v.encode("max feed-in power", 100)?,
+            Register::MaxChargeCurrent(v) => v.encode("max charge current")?,
+            Register::MaxDischargePower(v) => v.encode("max discharge power", 10)?,
+        })
+    }
+
+    /// Decode a raw value into the same kind of register
+    fn decode(self, raw: u16) -> Result<Register, VictronError> {
+        Ok(match self {
+            Register::PowerSetPoint(l, _) => Register::PowerSetPoint(l, raw as i16),
+            Register::DisableCharge(_) => Register::DisableCharge(decode_bool(raw)),
+            Register::DisableFeedIn(_) => Register::DisableFeedIn(decode_bool(raw)),
+            Register::Mode(_) => Register::Mode(Hub4Mode::try_from(raw)?),
+            Register::MaxFeedInPower(_) => Register::MaxFeedInPower(PowerLimit::decode(raw, 100)),
+            Register::MaxChargeCurrent(_) => Register::MaxChargeCurrent(CurrentLimit::decode(raw)),
+            Register::MaxDischargePower(_) => Register::MaxDischargePower(PowerLimit::decode(raw, 10)),
+        })
+    }
+}
+
+/// ESS power limit setting, stored as -1 when there is no limit
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum PowerLimit {
+    Unlimited,
+    Watts(u32),
+}
+
+impl PowerLimit {
+    fn encode(self, what: &'static str, step: u32) -> Result<u16, VictronError> {
+        match self {
+            PowerLimit::Unlimited => Ok(-1i16 as u16),
+            PowerLimit::Watts(w) => {
+                if w % step != 0 || w / step > i16::MAX as u32 {
+                    return Err(VictronError::OutOfRange { what, value: w });
+                }
+                Ok((w / step) as u16)
+            }
+        }
+    }
+
+    fn decode(raw: u16, step: u32) -> PowerLimit {
+        match raw as i16 {
+            v if v < 0 => PowerLimit::Unlimited,
+            v => PowerLimit::Watts(v as u32 * step),
+        }
+    }
+}
+
+/// DVCC current limit setting, stored as -1 when there is no limit
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum CurrentLimit {
+    Unlimited,
+    Amps(u32),
+}
+
+impl CurrentLimit {
+    fn encode(self, what: &'static str) -> Result<u16, VictronError> {
+        match self {
+            CurrentLimit::Unlimited => Ok(-1i16 as u16),
+            CurrentLimit::Amps(a) => {
+                if a > i16::MAX as u32 {
+                    return Err(VictronError::OutOfRange { what, value: a });
+                }
+                Ok(a as u16)
+            }
+        }
+    }
+
+    fn decode(raw: u16) -> CurrentLimit {
+        match raw as i16 {
+            v if v < 0 => CurrentLimit::Unlimited,
+            v => CurrentLimit::Amps(v as u32),
+        }
+    }
+}
+
+fn encode_bool(v: bool) -> u16 {
+    v as u16
+}
+
+/// Any non-zero value is true, older installs have 100 in these registers
+fn decode_bool(value: u16) -> bool {
+    value != 0
 }
 
 impl VictronESS {
-    pub async fn new(addr: SocketAddr, unit: u8) -> Result<Self, VictronError> {
-        let mut cli = VictronClient::new(addr).await?;
-        cli.set_unit(unit);
-        Ok(Self { client: cli })
+    pub fn new(conn: &VictronConnection, unit: u8, settings: u8) -> Self {
+        Self {
+            client: VictronClient::new(conn, unit),
+            settings: VictronClient::new(conn, settings),
+            shadow: BTreeMap::new(),
+            max_age: Duration::try_seconds(30).unwrap(),
+        }
+    }
+
+    /// Set how long an unchanged value is skipped before it is written again
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.client.state().await
     }
 
     pub async fn get_param(&mut self, reg: Register) -> Result<Register, VictronError> {
         let addr = self.map_register(&reg);
-        Ok(match reg {
-            Register::PowerSetPoint(l, _) => {
-                Register::PowerSetPoint(l, self.client.read_i16(addr).await?)
-            }
-            Register::DisableCharge(_) => {
-                Register::DisableCharge(self.client.read_u16(addr).await? as u8 == 1)
-            }
-            Register::DisableFeedIn(_) => {
-                Register::DisableFeedIn(self.client.read_u16(addr).await? as u8 == 1)
-            }
-            Register::Mode(_) => {
-                Register::Mode(Hub4Mode::try_from(self.client.read_u16(addr).await?)?)
-            }
+        let raw = self.client_for(&reg).read_u16(addr).await?;
+        reg.decode(raw)
+    }
+
+    pub async fn get_mode(&mut self) -> Result<Hub4Mode, VictronError> {
+        let addr = self.map_register(&Register::Mode(Hub4Mode::External));
+        Hub4Mode::try_from(self.settings.read_u16(addr).await?)
+    }
+
+    /// Read the setpoint and charge/feed-in control registers in one request
+    pub async fn get_params(&mut self) -> Result<Vec<Register>, VictronError> {
+        let start = self.map_register(&Register::PowerSetPoint(Line::L1, 0));
+        let end = self.map_register(&Register::PowerSetPoint(Line::L3, 0));
+        let block = self.client.read_block(start, end - start + 1).await?;
+
+        [
+            Register::PowerSetPoint(Line::L1, 0),
+            Register::PowerSetPoint(Line::L2, 0),
+            Register::PowerSetPoint(Line::L3, 0),
+            Register::DisableCharge(false),
+            Register::DisableFeedIn(false),
+        ]
+        .into_iter()
+        .map(|reg| {
+            let raw = block.u16(self.map_register(&reg))?;
+            reg.decode(raw)
         })
+        .collect()
     }
 
     pub async fn set_param(&mut self, reg: Register) -> Result<(), VictronError> {
+        self.write(reg, None).await
+    }
+
+    /// Write `reg` unless it already holds the last value written and that
+    /// write is younger than the max age, returns true if a write was made
+    pub async fn update_param(&mut self, reg: Register, now: DateTime<Utc>) -> Result<bool, VictronError> {
+        match self.shadow.get(&self.map_register(&reg)) {
+            Some((r, Some(t))) if *r == reg && now - *t < self.max_age => Ok(false),
+            _ => {
+                self.write(reg, Some(now)).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn write(&mut self, reg: Register, now: Option<DateTime<Utc>>) -> Result<(), VictronError> {
         let addr = self.map_register(&reg);
-        Ok(match reg {
-            Register::PowerSetPoint(_, power) => self.client.write_i16(addr, power).await?,
-            Register::DisableCharge(v) => {
-                self.client.write_u16(addr, if v { 100 } else { 0 }).await?
+        let value = reg.encode()?;
+        self.client_for(&reg).write_u16(addr, value).await?;
+        self.shadow.insert(addr, (reg, now));
+        Ok(())
+    }
+
+    /// Read back every register written so far and return those changed by
+    /// someone else, they are dropped from the shadow so the next
+    /// [VictronESS::update_param] writes them again
+    pub async fn verify(&mut self) -> Result<Vec<Drift>, VictronError> {
+        let mut drift = vec![];
+        for (addr, (reg, _)) in self.shadow.clone() {
+            let actual = self.client_for(&reg).read_u16(addr).await?;
+            if reg.clone().decode(actual).ok().as_ref() != Some(&reg) {
+                self.shadow.remove(&addr);
+                drift.push(Drift {
+                    expected: reg,
+                    actual,
+                });
             }
-            Register::DisableFeedIn(v) => {
-                self.client.write_u16(addr, if v { 100 } else { 0 }).await?
+        }
+        Ok(drift)
+    }
+
+    fn client_for(&mut self, reg: &Register) -> &mut VictronClient {
+        match reg {
+            Register::PowerSetPoint(_, _) | Register::DisableCharge(_) | Register::DisableFeedIn(_) => {
+                &mut self.client
             }
-            Register::Mode(v) => self.client.write_u16(addr, v as u16).await?,
-        })
+            _ => &mut self.settings,
+        }
     }
 
     fn map_register(&self, reg: &Register) -> u16 {
@@ -104,6 +298,130 @@ impl VictronESS {
             Register::DisableCharge(_) => 38,
             Register::DisableFeedIn(_) => 39,
             Register::Mode(_) => 2902,
+            Register::MaxDischargePower(_) => 2704,
+            Register::MaxChargeCurrent(_) => 2705,
+            Register::MaxFeedInPower(_) => 2706,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::victron::sim::{Simulator, INVERTER, SYSTEM};
+
+    #[test]
+    fn encode_decode() {
+        for reg in [
+            Register::PowerSetPoint(Line::L2, -3000),
+            Register::DisableCharge(true),
+            Register::DisableCharge(false),
+            Register::DisableFeedIn(true),
+            Register::Mode(Hub4Mode::External),
+            Register::MaxFeedInPower(PowerLimit::Unlimited),
+            Register::MaxFeedInPower(PowerLimit::Watts(5000)),
+            Register::MaxChargeCurrent(CurrentLimit::Amps(70)),
+            Register::MaxChargeCurrent(CurrentLimit::Unlimited),
+            Register::MaxDischargePower(PowerLimit::Watts(0)),
+        ] {
+            let raw = reg.encode().unwrap();
+            assert_eq!(reg.clone().decode(raw).unwrap(), reg, "raw {}", raw);
+        }
+
+        assert_eq!(Register::DisableFeedIn(true).encode().unwrap(), 1);
+        assert_eq!(Register::MaxChargeCurrent(CurrentLimit::Unlimited).encode().unwrap(), 0xffff);
+        assert_eq!(Register::MaxChargeCurrent(CurrentLimit::Amps(50)).encode().unwrap(), 50);
+        assert_eq!(Register::DisableCharge(false).decode(100).unwrap(), Register::DisableCharge(true));
+        assert!(matches!(
+            Register::MaxFeedInPower(PowerLimit::Watts(150)).encode(),
+            Err(VictronError::OutOfRange { what: "max feed-in power", value: 150 })
+        ));
+        assert!(Register::MaxDischargePower(PowerLimit::Watts(400_000)).encode().is_err());
+        assert!(Register::MaxChargeCurrent(CurrentLimit::Amps(40_000)).encode().is_err());
+    }
+
+    #[tokio::test]
+    async fn settings() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ess = VictronESS::new(&conn, INVERTER, SYSTEM);
+
+        let limit = Register::MaxFeedInPower(PowerLimit::Watts(3000));
+        ess.set_param(limit.clone()).await.unwrap();
+        assert_eq!(sim.get(SYSTEM, 2706), Some(30));
+        assert_eq!(ess.get_param(Register::MaxFeedInPower(PowerLimit::Unlimited)).await.unwrap(), limit);
+        assert_eq!(
+            ess.get_param(Register::MaxChargeCurrent(CurrentLimit::Amps(0))).await.unwrap(),
+            Register::MaxChargeCurrent(CurrentLimit::Unlimited)
+        );
+
+        ess.set_param(Register::DisableCharge(true)).await.unwrap();
+        assert_eq!(sim.get(INVERTER, 38), Some(1));
+        assert_eq!(ess.get_params().await.unwrap()[3], Register::DisableCharge(true));
+    }
+
+    #[tokio::test]
+    async fn set_point() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ess = VictronESS::new(&conn, INVERTER, SYSTEM);
+
+        ess.set_param(Register::PowerSetPoint(Line::L1, -800)).await.unwrap();
+        assert_eq!(
+            ess.get_param(Register::PowerSetPoint(Line::L1, 0)).await.unwrap(),
+            Register::PowerSetPoint(Line::L1, -800)
+        );
+        assert_eq!(sim.get_i16(INVERTER, 12), Some(-80), "grid follows setpoint");
+
+        let params = ess.get_params().await.unwrap();
+        assert_eq!(params[0], Register::PowerSetPoint(Line::L1, -800));
+        assert_eq!(params[1], Register::PowerSetPoint(Line::L2, 0));
+    }
+
+    #[tokio::test]
+    async fn shadow() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ess = VictronESS::new(&conn, INVERTER, SYSTEM);
+        let now = Utc::now();
+
+        let sp = Register::PowerSetPoint(Line::L1, 200);
+        assert!(ess.update_param(sp.clone(), now).await.unwrap());
+        assert!(ess.update_param(Register::DisableCharge(true), now).await.unwrap());
+        assert!(!ess.update_param(sp.clone(), now).await.unwrap(), "unchanged");
+        assert!(ess.verify().await.unwrap().is_empty());
+
+        // 100 written by older installs still reads as disabled
+        sim.set(INVERTER, 38, 100);
+        assert!(ess.verify().await.unwrap().is_empty());
+
+        // changed on the GX
+        sim.set(INVERTER, 37, 0);
+        assert!(!ess.update_param(sp.clone(), now).await.unwrap(), "drift not seen yet");
+        let drift = ess.verify().await.unwrap();
+        assert_eq!(drift, vec![Drift { expected: sp.clone(), actual: 0 }]);
+
+        assert!(ess.update_param(sp.clone(), now).await.unwrap(), "re-asserted");
+        assert_eq!(sim.get_i16(INVERTER, 37), Some(200));
+
+        // unchanged values are written again once the last write is too old
+        let later = now + Duration::try_seconds(29).unwrap();
+        assert!(!ess.update_param(sp.clone(), later).await.unwrap());
+        let later = now + Duration::try_seconds(30).unwrap();
+        assert!(ess.update_param(sp, later).await.unwrap(), "refreshed");
+    }
+
+    #[tokio::test]
+    async fn hub4_mode() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut ess = VictronESS::new(&conn, INVERTER, SYSTEM);
+
+        ess.set_param(Register::Mode(Hub4Mode::External)).await.unwrap();
+        assert_eq!(sim.get(SYSTEM, 2902), Some(3));
+        assert_eq!(
+            ess.get_param(Register::Mode(Hub4Mode::WithPhaseCompensation)).await.unwrap(),
+            Register::Mode(Hub4Mode::External)
+        );
+    }
+}
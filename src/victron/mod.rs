@@ -2,9 +2,110 @@ pub mod client;
 pub mod ess;
 pub mod ve_bus;
 pub mod ve_battery;
+pub mod ve_solar;
+pub mod ve_system;
+
+#[cfg(test)]
+pub mod sim;
+
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+pub use tokio_modbus::Exception;
 
 #[derive(Debug)]
-pub struct VictronError(pub String);
+pub enum VictronError {
+    /// Connecting to or talking to the device failed
+    Transport(std::io::Error),
+
+    /// Connection was lost and the next reconnect attempt is not due yet
+    NotConnected,
+
+    /// No response within the request timeout
+    Timeout(Duration),
+
+    /// Device answered with a Modbus exception
+    Exception(Exception),
+
+    /// Device returned a value which doesn't map to a known enum value
+    UnknownValue { what: &'static str, value: u16 },
+
+    /// Register doesn't exist for this line
+    RegisterUnavailable { register: &'static str, line: Line },
+
+    /// Inverter is not connected to any AC input
+    NoActiveInput,
+
+    /// Register was decoded from a block which doesn't contain it
+    NotInBlock { addr: u16, start: u16, end: Option<u16> },
+
+    /// Device returned fewer registers than requested
+    ShortRead { expected: u16, got: u16 },
+
+    /// Value can't be represented by the register it is written to
+    OutOfRange { what: &'static str, value: u32 },
+}
+
+impl VictronError {
+    /// Errors which are expected to go away when the request is repeated
+    pub fn is_transient(&self) -> bool {
+        match self {
+            VictronError::Transport(_) | VictronError::NotConnected | VictronError::Timeout(_) => {
+                true
+            }
+            VictronError::Exception(e) => matches!(
+                e,
+                Exception::ServerDeviceBusy
+                    | Exception::Acknowledge
+                    | Exception::GatewayPathUnavailable
+                    | Exception::GatewayTargetDevice
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl Display for VictronError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VictronError::Transport(e) => write!(f, "Transport error: {}", e),
+            VictronError::NotConnected => write!(f, "Not connected, waiting to reconnect"),
+            VictronError::Timeout(d) => write!(f, "No response after {} ms", d.as_millis()),
+            VictronError::Exception(e) => write!(f, "Modbus exception: {}", e),
+            VictronError::UnknownValue { what, value } => {
+                write!(f, "Unknown {} value {} from device", what, value)
+            }
+            VictronError::RegisterUnavailable { register, line } => {
+                write!(f, "Register {} not available on {:?}", register, line)
+            }
+            VictronError::NoActiveInput => write!(f, "No active input"),
+            VictronError::NotInBlock { addr, start, end: Some(end) } => {
+                write!(f, "Register {} not in block {}..={}", addr, start, end)
+            }
+            VictronError::NotInBlock { addr, start, end: None } => {
+                write!(f, "Register {} not in empty block at {}", addr, start)
+            }
+            VictronError::ShortRead { expected, got } => {
+                write!(f, "Expected {} registers, got {}", expected, got)
+            }
+            VictronError::OutOfRange { what, value } => {
+                write!(f, "Value {} out of range for {}", value, what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VictronError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VictronError::Transport(e) => Some(e),
+            VictronError::Exception(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum Side {
@@ -12,7 +113,7 @@ pub enum Side {
     Output,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Line {
     L1 = 1,
     L2 = 2,
@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::{ready, Ready};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_modbus::prelude::{Exception, Request, Response, SlaveRequest};
+use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
+use tokio_modbus::server::Service;
+
+use crate::victron::Line;
+
+pub const SYSTEM: u8 = 100;
+pub const BATTERY: u8 = 225;
+pub const INVERTER: u8 = 227;
+pub const SOLAR: [u8; 2] = [226, 224];
+
+/// In-process Modbus TCP server emulating the register map of a Venus GX
+pub struct Simulator {
+    addr: SocketAddr,
+    registers: Arc<Mutex<Registers>>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Registers {
+    units: HashMap<u8, BTreeMap<u16, u16>>,
+
+    /// Number of successful writes to each unit and address
+    writes: HashMap<(u8, u16), usize>,
+}
+
+#[derive(Clone)]
+struct SimService {
+    registers: Arc<Mutex<Registers>>,
+}
+
+impl Simulator {
+    pub async fn start() -> Simulator {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registers = Arc::new(Mutex::new(Registers::gx()));
+
+        let service = SimService {
+            registers: registers.clone(),
+        };
+        let task = tokio::spawn(async move {
+            let server = Server::new(listener);
+            let on_connected = |stream, socket_addr| {
+                let service = service.clone();
+                async move {
+                    accept_tcp_connection(stream, socket_addr, |_| Ok(Some(service.clone())))
+                }
+            };
+            let _ = server.serve(&on_connected, |_| {}).await;
+        });
+
+        Simulator {
+            addr,
+            registers,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn get(&self, unit: u8, addr: u16) -> Option<u16> {
+        let regs = self.registers.lock().unwrap();
+        regs.units.get(&unit).and_then(|u| u.get(&addr)).copied()
+    }
+
+    pub fn get_i16(&self, unit: u8, addr: u16) -> Option<i16> {
+        self.get(unit, addr).map(|v| v as i16)
+    }
+
+    /// Set a register directly, bypassing write access checks and side effects
+    pub fn set(&self, unit: u8, addr: u16, value: u16) {
+        let mut regs = self.registers.lock().unwrap();
+        regs.units.entry(unit).or_default().insert(addr, value);
+    }
+
+    /// Number of times a register was written over Modbus
+    pub fn writes(&self, unit: u8, addr: u16) -> usize {
+        let regs = self.registers.lock().unwrap();
+        regs.writes.get(&(unit, addr)).copied().unwrap_or(0)
+    }
+
+    /// Remove a register, reads of it fail with [Exception::IllegalDataAddress]
+    pub fn remove(&self, unit: u8, addr: u16) {
+        let mut regs = self.registers.lock().unwrap();
+        if let Some(u) = regs.units.get_mut(&unit) {
+            u.remove(&addr);
+        }
+    }
+
+    /// Set the inverter AC output (house load) power in watts, the system
+    /// consumption follows as there are no loads on the input side
+    pub fn set_output_power(&self, line: Line, watts: f32) {
+        self.set(INVERTER, 22 + line as u16, (watts / 10.0) as i16 as u16);
+        self.set(SYSTEM, 816 + line as u16, watts as u16);
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Registers {
+    /// Single phase system with a VE.Bus inverter, battery monitor, two MPPTs and ESS
+    fn gx() -> Self {
+        let mut units: HashMap<u8, BTreeMap<u16, u16>> = HashMap::new();
+
+        // com.victronenergy.vebus
+        let vebus = units.entry(INVERTER).or_default();
+        for addr in 3..=71 {
+            vebus.insert(addr, 0);
+        }
+        vebus.insert(3, 2300); // input voltage L1
+        vebus.insert(9, 5000); // input frequency L1
+        vebus.insert(15, 2300); // output voltage L1
+        vebus.insert(21, 5000); // output frequency
+        vebus.insert(22, 160); // active input current limit
+        vebus.insert(23, 50); // output power L1
+        vebus.insert(26, 5200); // battery voltage
+        vebus.insert(28, 1); // phase count
+        vebus.insert(30, 800); // soc
+        vebus.insert(31, 9); // state: inverting
+        vebus.insert(33, 3); // mode: on
+
+        // com.victronenergy.battery
+        let battery = units.entry(BATTERY).or_default();
+        for addr in (258..=309).chain(318..=326) {
+            battery.insert(addr, 0);
+        }
+        battery.insert(259, 5200); // voltage
+        battery.insert(262, 250); // temperature
+        battery.insert(265, 280); // consumed Ah
+        battery.insert(266, 800); // soc
+        battery.insert(304, 1000); // soh
+        battery.insert(305, 568); // max charge voltage
+        battery.insert(307, 700); // charge current limit
+        battery.insert(308, 1000); // discharge current limit
+        battery.insert(309, 1400); // capacity
+        battery.insert(1290, 325); // min cell voltage
+        battery.insert(1291, 327); // max cell voltage
+
+        // com.victronenergy.solarcharger
+        for unit in SOLAR {
+            let mppt = units.entry(unit).or_default();
+            for addr in 771..=791 {
+                mppt.insert(addr, 0);
+            }
+            mppt.insert(771, 5200); // battery voltage
+            mppt.insert(772, 230); // battery current
+            mppt.insert(775, 3); // state: bulk
+            mppt.insert(776, 12000); // pv voltage
+            mppt.insert(777, 100); // pv current
+            mppt.insert(784, 42); // yield today
+            mppt.insert(789, 12000); // pv power
+        }
+
+        // com.victronenergy.system
+        let system = units.entry(SYSTEM).or_default();
+        for addr in (808..=826).chain(840..=846) {
+            system.insert(addr, 0);
+        }
+        system.insert(817, 500); // consumption L1
+        system.insert(840, 520); // battery voltage
+        system.insert(843, 80); // soc
+        system.insert(850, 2400); // dc pv
+
+        // com.victronenergy.settings
+        system.insert(2704, -1i16 as u16); // max discharge power: unlimited
+        system.insert(2705, -1i16 as u16); // max charge current: unlimited
+        system.insert(2706, -1i16 as u16); // max feed-in power: unlimited
+        system.insert(2901, 100); // ess min soc
+        system.insert(2902, 1); // hub4 mode
+
+        Self {
+            units,
+            writes: HashMap::new(),
+        }
+    }
+
+    fn writable(unit: u8, addr: u16) -> bool {
+        match unit {
+            INVERTER => matches!(addr, 33 | 37..=41 | 69 | 70),
+            SYSTEM => matches!(addr, 2704..=2706 | 2902),
+            _ => false,
+        }
+    }
+
+    fn read(&self, unit: u8, addr: u16, cnt: u16) -> Result<Vec<u16>, Exception> {
+        let regs = self
+            .units
+            .get(&unit)
+            .ok_or(Exception::GatewayTargetDevice)?;
+        (addr..addr + cnt)
+            .map(|a| regs.get(&a).copied().ok_or(Exception::IllegalDataAddress))
+            .collect()
+    }
+
+    fn write(&mut self, unit: u8, addr: u16, value: u16) -> Result<(), Exception> {
+        let regs = self
+            .units
+            .get_mut(&unit)
+            .ok_or(Exception::GatewayTargetDevice)?;
+        if !regs.contains_key(&addr) || !Self::writable(unit, addr) {
+            return Err(Exception::IllegalDataAddress);
+        }
+        regs.insert(addr, value);
+        *self.writes.entry((unit, addr)).or_default() += 1;
+        if unit == INVERTER && matches!(addr, 37..=41) {
+            self.apply_setpoints();
+        }
+        Ok(())
+    }
+
+    /// The grid meter follows the ESS setpoint, feed-in only when allowed
+    fn apply_setpoints(&mut self) {
+        let regs = self.units.get_mut(&INVERTER).unwrap();
+        let feed_in_disabled = regs[&39] != 0;
+        let mut grid = vec![];
+        for (line, sp_addr) in [(Line::L1, 37), (Line::L2, 40), (Line::L3, 41)] {
+            let mut sp = regs[&sp_addr] as i16;
+            if feed_in_disabled {
+                sp = sp.max(0);
+            }
+            regs.insert(11 + line as u16, (sp / 10) as u16);
+            grid.push((819 + line as u16, sp as u16));
+        }
+        if let Some(system) = self.units.get_mut(&SYSTEM) {
+            system.extend(grid);
+        }
+    }
+}
+
+impl Service for SimService {
+    type Request = SlaveRequest<'static>;
+    type Future = Ready<Result<Response, Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let mut regs = self.registers.lock().unwrap();
+        let unit = req.slave;
+        ready(match req.request {
+            Request::ReadInputRegisters(addr, cnt) => {
+                regs.read(unit, addr, cnt).map(Response::ReadInputRegisters)
+            }
+            Request::ReadHoldingRegisters(addr, cnt) => {
+                regs.read(unit, addr, cnt).map(Response::ReadHoldingRegisters)
+            }
+            Request::WriteSingleRegister(addr, v) => regs
+                .write(unit, addr, v)
+                .map(|_| Response::WriteSingleRegister(addr, v)),
+            Request::WriteMultipleRegisters(addr, words) => {
+                for (i, v) in words.iter().enumerate() {
+                    if let Err(e) = regs.write(unit, addr + i as u16, *v) {
+                        return ready(Err(e));
+                    }
+                }
+                Ok(Response::WriteMultipleRegisters(addr, words.len() as u16))
+            }
+            _ => Err(Exception::IllegalFunction),
+        })
+    }
+}
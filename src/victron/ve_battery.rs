@@ -1,20 +1,519 @@
-use std::net::SocketAddr;
-use crate::victron::client::VictronClient;
-use crate::VictronError;
+use std::time::Duration;
+
+use crate::victron::client::{ConnectionState, RegisterBlock, VictronClient, VictronConnection};
+use crate::victron::ve_bus::AlarmState;
+use crate::victron::VictronError;
 
 pub struct VictronBattery {
     client: VictronClient,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Register {
+    Power,
+    Voltage,
+    StarterVoltage,
+    Current,
+    Temperature,
+    MidVoltage,
+    MidVoltageDeviation,
+    ConsumedAh,
+    Soc,
+    Alarm(BatteryAlarm),
+    History(History),
+    TimeToGo,
+    Soh,
+    MaxChargeVoltage,
+    LowVoltage,
+    ChargeCurrentLimit,
+    DischargeCurrentLimit,
+    Capacity,
+    MinCellTemperature,
+    MaxCellTemperature,
+    MinCellVoltage,
+    MaxCellVoltage,
+}
+
+/// Battery monitor and BMS alarms
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BatteryAlarm {
+    /// Any alarm is active, [AlarmState::Warning] is not used
+    Alarm,
+    LowVoltage,
+    HighVoltage,
+    LowStarterVoltage,
+    HighStarterVoltage,
+    LowSoc,
+    LowTemperature,
+    HighTemperature,
+    MidVoltage,
+    LowFusedVoltage,
+    HighFusedVoltage,
+    FuseBlown,
+    HighInternalTemperature,
+    HighChargeCurrent,
+    HighDischargeCurrent,
+    CellImbalance,
+    InternalFailure,
+    HighChargeTemperature,
+    LowChargeTemperature,
+    LowCellVoltage,
+}
+
+/// Alarms in register order, the first 13 and last 7 are contiguous
+const ALL_ALARMS: [BatteryAlarm; 20] = {
+    use BatteryAlarm::*;
+    [
+        Alarm,
+        LowVoltage,
+        HighVoltage,
+        LowStarterVoltage,
+        HighStarterVoltage,
+        LowSoc,
+        LowTemperature,
+        HighTemperature,
+        MidVoltage,
+        LowFusedVoltage,
+        HighFusedVoltage,
+        FuseBlown,
+        HighInternalTemperature,
+        HighChargeCurrent,
+        HighDischargeCurrent,
+        CellImbalance,
+        InternalFailure,
+        HighChargeTemperature,
+        LowChargeTemperature,
+        LowCellVoltage,
+    ]
+};
+
+/// Battery monitor history counters
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum History {
+    DeepestDischarge,
+    LastDischarge,
+    AverageDischarge,
+    ChargeCycles,
+    FullDischarges,
+    TotalAhDrawn,
+    MinimumVoltage,
+    MaximumVoltage,
+    TimeSinceLastFullCharge,
+    AutomaticSyncs,
+    LowVoltageAlarms,
+    HighVoltageAlarms,
+    DischargedEnergy,
+    ChargedEnergy,
+}
+
+/// Battery monitor values used by the controller
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BatteryState {
+    /// Battery voltage in volts
+    pub voltage: f32,
+
+    /// Battery current in amps, negative when discharging
+    pub current: f32,
+
+    /// Battery temperature in degrees celsius
+    pub temperature: f32,
+
+    /// Amp hours drawn from the battery since it was last full
+    pub consumed_ah: f32,
+
+    /// State of charge percent
+    pub soc: f32,
+
+    /// Installed capacity in amp hours
+    pub capacity: f32,
+
+    /// BMS charge current limit (CCL) in amps, [None] for monitors without a BMS
+    pub charge_current_limit: Option<f32>,
+
+    /// BMS discharge current limit (DCL) in amps, [None] for monitors without a BMS
+    pub discharge_current_limit: Option<f32>,
+}
+
+impl BatteryState {
+    /// Installed capacity in kWh at the battery's `nominal_voltage`, the live
+    /// voltage would make the capacity follow the state of charge
+    pub fn capacity_kwh(&self, nominal_voltage: f32) -> f32 {
+        self.capacity * nominal_voltage / 1000.0
+    }
+}
+
+/// State of every battery alarm
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryAlarms {
+    pub alarms: Vec<(BatteryAlarm, AlarmState)>,
+}
+
+impl BatteryAlarms {
+    pub fn get(&self, alarm: BatteryAlarm) -> AlarmState {
+        self.alarms
+            .iter()
+            .find(|(a, _)| *a == alarm)
+            .map(|(_, s)| *s)
+            .unwrap_or(AlarmState::Ok)
+    }
+
+    /// Alarms which are not [AlarmState::Ok]
+    pub fn active(&self) -> Vec<(BatteryAlarm, AlarmState)> {
+        self.alarms
+            .iter()
+            .filter(|(_, s)| *s != AlarmState::Ok)
+            .copied()
+            .collect()
+    }
+
+    /// Most severe state of any alarm
+    pub fn worst(&self) -> AlarmState {
+        self.alarms
+            .iter()
+            .map(|(_, s)| *s)
+            .max()
+            .unwrap_or(AlarmState::Ok)
+    }
+}
+
+/// Battery monitor history, amp hours are drawn from the battery
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BatteryHistory {
+    pub deepest_discharge: f32,
+    pub last_discharge: f32,
+    pub average_discharge: f32,
+    pub charge_cycles: u16,
+    pub full_discharges: u16,
+    pub total_ah_drawn: f32,
+
+    /// Lowest and highest battery voltage in volts
+    pub minimum_voltage: f32,
+    pub maximum_voltage: f32,
+
+    pub time_since_last_full_charge: Duration,
+    pub automatic_syncs: u16,
+    pub low_voltage_alarms: u16,
+    pub high_voltage_alarms: u16,
+
+    /// Energy in kWh
+    pub discharged_energy: f32,
+    pub charged_energy: f32,
+}
+
 impl VictronBattery {
-    pub async fn new(addr: SocketAddr, unit: u8) -> Result<Self, VictronError> {
-        let mut cli = VictronClient::new(addr).await?;
-        cli.set_unit(unit);
-        Ok(Self { client: cli })
+    pub fn new(conn: &VictronConnection, unit: u8) -> Self {
+        Self {
+            client: VictronClient::new(conn, unit),
+        }
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.client.state().await
+    }
+
+    /// Battery voltage in volts
+    pub async fn voltage(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Voltage).await? as f32 / 100.0)
+    }
+
+    /// Battery current in amps, negative when discharging
+    pub async fn current(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Current).await? as i16 as f32 / 10.0)
+    }
+
+    /// Battery power in watts, negative when discharging
+    pub async fn power(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Power).await? as i16 as f32)
+    }
+
+    /// State of charge percent
+    pub async fn soc(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Soc).await? as f32 / 10.0)
+    }
+
+    /// State of health percent
+    pub async fn soh(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Soh).await? as f32 / 10.0)
+    }
+
+    /// Amp hours drawn from the battery since it was last full
+    pub async fn consumed_ah(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::ConsumedAh).await? as f32 / 10.0)
+    }
+
+    /// Estimated time until the battery is empty at the current load
+    pub async fn time_to_go(&mut self) -> Result<Duration, VictronError> {
+        Ok(Duration::from_secs(self.get(Register::TimeToGo).await? as u64 * 100))
+    }
+
+    /// Battery temperature in degrees celsius
+    pub async fn temperature(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Temperature).await? as i16 as f32 / 10.0)
+    }
+
+    /// Lowest cell voltage in volts
+    pub async fn min_cell_voltage(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::MinCellVoltage).await? as f32 / 100.0)
+    }
+
+    /// Highest cell voltage in volts
+    pub async fn max_cell_voltage(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::MaxCellVoltage).await? as f32 / 100.0)
+    }
+
+    /// Lowest cell temperature in degrees celsius
+    pub async fn min_cell_temperature(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::MinCellTemperature).await? as i16 as f32 / 10.0)
+    }
+
+    /// Highest cell temperature in degrees celsius
+    pub async fn max_cell_temperature(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::MaxCellTemperature).await? as i16 as f32 / 10.0)
+    }
+
+    /// BMS charge voltage limit in volts
+    pub async fn max_charge_voltage(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::MaxChargeVoltage).await? as f32 / 10.0)
     }
 
+    /// BMS charge current limit (CCL) in amps
+    pub async fn charge_current_limit(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::ChargeCurrentLimit).await? as f32 / 10.0)
+    }
+
+    /// BMS discharge current limit (DCL) in amps
+    pub async fn discharge_current_limit(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::DischargeCurrentLimit).await? as f32 / 10.0)
+    }
+
+    /// Installed capacity in amp hours
     pub async fn capacity(&mut self) -> Result<f32, VictronError> {
-        let v = self.client.read_u16(309).await?;
-        Ok(v as f32 / 10.0)
+        Ok(self.get(Register::Capacity).await? as f32 / 10.0)
+    }
+
+    /// Read voltage, current, temperature, consumed Ah and SoC in one request
+    /// and the BMS limits and installed capacity in a second
+    pub async fn state(&mut self) -> Result<BatteryState, VictronError> {
+        let block = self.read(Register::Voltage, Register::Soc).await?;
+        let limits = match self.read(Register::ChargeCurrentLimit, Register::Capacity).await {
+            Ok(b) => b,
+            // plain battery monitors have no BMS limits
+            Err(VictronError::Exception(_)) => self.read(Register::Capacity, Register::Capacity).await?,
+            Err(e) => return Err(e),
+        };
+        let limit = |reg| {
+            let addr = self.get_register(reg);
+            limits.contains(addr).then(|| limits.u16(addr).map(|v| v as f32 / 10.0))
+        };
+
+        Ok(BatteryState {
+            voltage: block.u16(self.get_register(Register::Voltage))? as f32 / 100.0,
+            current: block.i16(self.get_register(Register::Current))? as f32 / 10.0,
+            temperature: block.i16(self.get_register(Register::Temperature))? as f32 / 10.0,
+            consumed_ah: block.u16(self.get_register(Register::ConsumedAh))? as f32 / 10.0,
+            soc: block.u16(self.get_register(Register::Soc))? as f32 / 10.0,
+            capacity: limits.u16(self.get_register(Register::Capacity))? as f32 / 10.0,
+            charge_current_limit: limit(Register::ChargeCurrentLimit).transpose()?,
+            discharge_current_limit: limit(Register::DischargeCurrentLimit).transpose()?,
+        })
+    }
+
+    /// Read every alarm in two requests
+    pub async fn alarms(&mut self) -> Result<BatteryAlarms, VictronError> {
+        use BatteryAlarm::*;
+        let monitor = self
+            .read(Register::Alarm(Alarm), Register::Alarm(HighInternalTemperature))
+            .await?;
+        let bms = self
+            .read(Register::Alarm(HighChargeCurrent), Register::Alarm(LowCellVoltage))
+            .await?;
+
+        let alarms = ALL_ALARMS
+            .iter()
+            .map(|a| {
+                let addr = self.get_register(Register::Alarm(*a));
+                let block = if monitor.contains(addr) { &monitor } else { &bms };
+                Ok((*a, AlarmState::try_from(block.u16(addr)? as u8)?))
+            })
+            .collect::<Result<Vec<_>, VictronError>>()?;
+        Ok(BatteryAlarms { alarms })
     }
-}
\ No newline at end of file
+
+    /// Read every history counter in one request
+    pub async fn history(&mut self) -> Result<BatteryHistory, VictronError> {
+        use History::*;
+        let block = self
+            .read(Register::History(DeepestDischarge), Register::History(ChargedEnergy))
+            .await?;
+        let h = |h: History| block.u16(self.get_register(Register::History(h)));
+
+        Ok(BatteryHistory {
+            deepest_discharge: h(DeepestDischarge)? as f32 / 10.0,
+            last_discharge: h(LastDischarge)? as f32 / 10.0,
+            average_discharge: h(AverageDischarge)? as f32 / 10.0,
+            charge_cycles: h(ChargeCycles)?,
+            full_discharges: h(FullDischarges)?,
+            total_ah_drawn: h(TotalAhDrawn)? as f32 / 10.0,
+            minimum_voltage: h(MinimumVoltage)? as f32 / 100.0,
+            maximum_voltage: h(MaximumVoltage)? as f32 / 100.0,
+            time_since_last_full_charge: Duration::from_secs(
+                h(TimeSinceLastFullCharge)? as u64 * 100,
+            ),
+            automatic_syncs: h(AutomaticSyncs)?,
+            low_voltage_alarms: h(LowVoltageAlarms)?,
+            high_voltage_alarms: h(HighVoltageAlarms)?,
+            discharged_energy: h(DischargedEnergy)? as f32 / 10.0,
+            charged_energy: h(ChargedEnergy)? as f32 / 10.0,
+        })
+    }
+
+    pub async fn get(&mut self, reg: Register) -> Result<u16, VictronError> {
+        self.client.read_u16(self.get_register(reg)).await
+    }
+
+    /// Read all registers from `first` to `last` inclusive in one request
+    async fn read(&mut self, first: Register, last: Register) -> Result<RegisterBlock, VictronError> {
+        let start = self.get_register(first);
+        let end = self.get_register(last);
+        self.client.read_block(start, end - start + 1).await
+    }
+
+    fn get_register(&self, reg: Register) -> u16 {
+        use Register::*;
+        match reg {
+            Power => 258,
+            Voltage => 259,
+            StarterVoltage => 260,
+            Current => 261,
+            Temperature => 262,
+            MidVoltage => 263,
+            MidVoltageDeviation => 264,
+            ConsumedAh => 265,
+            Soc => 266,
+            Alarm(a) => match a {
+                BatteryAlarm::Alarm => 267,
+                BatteryAlarm::LowVoltage => 268,
+                BatteryAlarm::HighVoltage => 269,
+                BatteryAlarm::LowStarterVoltage => 270,
+                BatteryAlarm::HighStarterVoltage => 271,
+                BatteryAlarm::LowSoc => 272,
+                BatteryAlarm::LowTemperature => 273,
+                BatteryAlarm::HighTemperature => 274,
+                BatteryAlarm::MidVoltage => 275,
+                BatteryAlarm::LowFusedVoltage => 276,
+                BatteryAlarm::HighFusedVoltage => 277,
+                BatteryAlarm::FuseBlown => 278,
+                BatteryAlarm::HighInternalTemperature => 279,
+                BatteryAlarm::HighChargeCurrent => 320,
+                BatteryAlarm::HighDischargeCurrent => 321,
+                BatteryAlarm::CellImbalance => 322,
+                BatteryAlarm::InternalFailure => 323,
+                BatteryAlarm::HighChargeTemperature => 324,
+                BatteryAlarm::LowChargeTemperature => 325,
+                BatteryAlarm::LowCellVoltage => 326,
+            },
+            History(h) => match h {
+                self::History::DeepestDischarge => 281,
+                self::History::LastDischarge => 282,
+                self::History::AverageDischarge => 283,
+                self::History::ChargeCycles => 284,
+                self::History::FullDischarges => 285,
+                self::History::TotalAhDrawn => 286,
+                self::History::MinimumVoltage => 287,
+                self::History::MaximumVoltage => 288,
+                self::History::TimeSinceLastFullCharge => 289,
+                self::History::AutomaticSyncs => 290,
+                self::History::LowVoltageAlarms => 291,
+                self::History::HighVoltageAlarms => 292,
+                self::History::DischargedEnergy => 301,
+                self::History::ChargedEnergy => 302,
+            },
+            TimeToGo => 303,
+            Soh => 304,
+            MaxChargeVoltage => 305,
+            LowVoltage => 306,
+            ChargeCurrentLimit => 307,
+            DischargeCurrentLimit => 308,
+            Capacity => 309,
+            MinCellTemperature => 318,
+            MaxCellTemperature => 319,
+            MinCellVoltage => 1290,
+            MaxCellVoltage => 1291,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::victron::sim::{Simulator, BATTERY};
+
+    #[tokio::test]
+    async fn state() {
+        let sim = Simulator::start().await;
+        sim.set(BATTERY, 261, -125i16 as u16);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bat = VictronBattery::new(&conn, BATTERY);
+
+        let s = bat.state().await.unwrap();
+        assert_eq!(s.voltage, 52.0);
+        assert_eq!(s.current, -12.5);
+        assert_eq!(s.temperature, 25.0);
+        assert_eq!(s.consumed_ah, 28.0);
+        assert_eq!(s.soc, 80.0);
+        assert_eq!(s.capacity, 140.0);
+        assert_eq!(s.capacity_kwh(48.0), 6.72);
+        assert_eq!(s.charge_current_limit, Some(70.0));
+        assert_eq!(s.discharge_current_limit, Some(100.0));
+
+        // battery monitor without a BMS
+        sim.remove(BATTERY, 307);
+        let s = bat.state().await.unwrap();
+        assert_eq!(s.capacity, 140.0);
+        assert_eq!(s.charge_current_limit, None);
+    }
+
+    #[tokio::test]
+    async fn monitor() {
+        let sim = Simulator::start().await;
+        sim.set(BATTERY, 258, -650i16 as u16);
+        sim.set(BATTERY, 303, 72); // 2 hours
+        sim.set(BATTERY, 319, -50i16 as u16);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bat = VictronBattery::new(&conn, BATTERY);
+
+        assert_eq!(bat.power().await.unwrap(), -650.0);
+        assert_eq!(bat.time_to_go().await.unwrap(), Duration::from_secs(7200));
+        assert_eq!(bat.max_cell_temperature().await.unwrap(), -5.0);
+        assert_eq!(bat.min_cell_voltage().await.unwrap(), 3.25);
+        assert_eq!(bat.max_cell_voltage().await.unwrap(), 3.27);
+        assert_eq!(bat.charge_current_limit().await.unwrap(), 70.0);
+        assert_eq!(bat.discharge_current_limit().await.unwrap(), 100.0);
+    }
+
+    #[tokio::test]
+    async fn alarms_and_history() {
+        let sim = Simulator::start().await;
+        sim.set(BATTERY, 268, 1); // low voltage warning
+        sim.set(BATTERY, 322, 2); // cell imbalance alarm
+        sim.set(BATTERY, 284, 42);
+        sim.set(BATTERY, 302, 1234);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bat = VictronBattery::new(&conn, BATTERY);
+
+        let alarms = bat.alarms().await.unwrap();
+        assert_eq!(alarms.alarms.len(), ALL_ALARMS.len());
+        assert_eq!(alarms.get(BatteryAlarm::LowVoltage), AlarmState::Warning);
+        assert_eq!(alarms.get(BatteryAlarm::CellImbalance), AlarmState::Alarm);
+        assert_eq!(alarms.active().len(), 2);
+        assert_eq!(alarms.worst(), AlarmState::Alarm);
+
+        let history = bat.history().await.unwrap();
+        assert_eq!(history.charge_cycles, 42);
+        assert_eq!(history.charged_energy, 123.4);
+        assert_eq!(history.deepest_discharge, 0.0);
+    }
+}
@@ -1,10 +1,14 @@
 use std::fmt::Display;
-use crate::victron::client::VictronClient;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::victron::client::{ConnectionState, RegisterBlock, VictronClient, VictronConnection};
 use crate::victron::{Line, LineDetail, Side, VictronError};
-use std::net::SocketAddr;
 
 pub struct VictronBus {
     client: VictronClient,
+
+    /// Last alarm snapshot, used to track transition times
+    alarms: Option<AlarmSnapshot>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -37,7 +41,7 @@ impl TryFrom<u8> for Mode {
             2 => Mode::InverterOnly,
             3 => Mode::On,
             4 => Mode::Off,
-            e => return Err(VictronError(format!("Invalid mode {}!", e))),
+            value => return Err(VictronError::UnknownValue { what: "mode", value: value as u16 }),
         })
     }
 }
@@ -59,9 +63,9 @@ pub enum State {
     BulkProtection = 252,
 }
 
-impl ToString for State {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
             State::Off => "Off",
             State::LowPower => "Low Power",
             State::Fault => "Fault",
@@ -75,8 +79,8 @@ impl ToString for State {
             State::PowerAssist => "Power Assist",
             State::PowerSupply => "Power Supply",
             State::BulkProtection => "Bulk Protection",
-        }
-        .to_owned()
+        };
+        write!(f, "{}", str)
     }
 }
 
@@ -98,7 +102,7 @@ impl TryFrom<u8> for State {
             10 => State::PowerAssist,
             11 => State::PowerSupply,
             252 => State::BulkProtection,
-            e => return Err(VictronError(format!("Invalid mode {}!", e))),
+            value => return Err(VictronError::UnknownValue { what: "state", value: value as u16 }),
         })
     }
 }
@@ -138,14 +142,27 @@ pub enum ActiveInput {
     Disconnected,
 }
 
-impl ToString for ActiveInput {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for ActiveInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
             ActiveInput::Line1 => "Line 1",
             ActiveInput::Line2 => "Line 2",
             ActiveInput::Disconnected => "Disconnected",
-        }
-        .to_owned()
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl TryFrom<u16> for ActiveInput {
+    type Error = VictronError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => ActiveInput::Line1,
+            1 => ActiveInput::Line2,
+            240 => ActiveInput::Disconnected,
+            value => return Err(VictronError::UnknownValue { what: "active input", value }),
+        })
     }
 }
 
@@ -156,12 +173,12 @@ impl TryInto<Line> for ActiveInput {
         Ok(match self {
             ActiveInput::Line1 => Line::L1,
             ActiveInput::Line2 => Line::L2,
-            _ => return Err(VictronError("No active input!".to_owned())),
+            _ => return Err(VictronError::NoActiveInput),
         })
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum AlarmState {
     Ok = 0,
     Warning = 1,
@@ -176,7 +193,7 @@ impl TryFrom<u8> for AlarmState {
             0 => AlarmState::Ok,
             1 => AlarmState::Warning,
             2 => AlarmState::Alarm,
-            e => return Err(VictronError(format!("Invalid alarm state {}!", e))),
+            value => return Err(VictronError::UnknownValue { what: "alarm state", value: value as u16 }),
         })
     }
 }
@@ -198,11 +215,242 @@ pub enum Alarm {
     GridLost(AlarmState),
 }
 
+impl Alarm {
+    pub fn state(&self) -> AlarmState {
+        use Alarm::*;
+        match *self {
+            HighTemperature(s)
+            | LowBattery(s)
+            | Overload(s)
+            | TemperatureSensor(s)
+            | VoltageSensor(s)
+            | LineTemperature(_, s)
+            | LineLowBattery(_, s)
+            | LineOverload(_, s)
+            | LineRipple(_, s)
+            | PhaseRotation(s)
+            | GridLost(s) => s,
+        }
+    }
+
+    /// Copy of this alarm with a new state
+    pub fn with_state(self, state: AlarmState) -> Alarm {
+        use Alarm::*;
+        match self {
+            HighTemperature(_) => HighTemperature(state),
+            LowBattery(_) => LowBattery(state),
+            Overload(_) => Overload(state),
+            TemperatureSensor(_) => TemperatureSensor(state),
+            VoltageSensor(_) => VoltageSensor(state),
+            LineTemperature(l, _) => LineTemperature(l, state),
+            LineLowBattery(l, _) => LineLowBattery(l, state),
+            LineOverload(l, _) => LineOverload(l, state),
+            LineRipple(l, _) => LineRipple(l, state),
+            PhaseRotation(_) => PhaseRotation(state),
+            GridLost(_) => GridLost(state),
+        }
+    }
+}
+
+/// All VE.Bus values read in a single request
+#[derive(Debug, Clone)]
+pub struct BusSnapshot {
+    /// AC input lines L1-L3
+    pub input: Vec<LineDetail>,
+
+    /// AC output lines L1-L3
+    pub output: Vec<LineDetail>,
+
+    /// Battery voltage in volts
+    pub battery_voltage: f32,
+
+    /// Battery current in amps, negative when discharging
+    pub battery_current: f32,
+
+    pub phase_count: u16,
+    pub active_input: ActiveInput,
+
+    /// State of charge percent
+    pub soc: f32,
+    pub state: State,
+    pub mode: Mode,
+    pub alarms: AlarmSnapshot,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AlarmStatus {
+    pub state: AlarmState,
+
+    /// Time of the transition into `state`, or when it was first read
+    pub since: DateTime<Utc>,
+}
+
+/// Per-line VE.Bus alarms
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LineAlarms {
+    pub temperature: AlarmStatus,
+    pub low_battery: AlarmStatus,
+    pub overload: AlarmStatus,
+    pub ripple: AlarmStatus,
+}
+
+/// State of every VE.Bus alarm at a point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmSnapshot {
+    /// When the alarms were read
+    pub time: DateTime<Utc>,
+
+    pub high_temperature: AlarmStatus,
+    pub low_battery: AlarmStatus,
+    pub overload: AlarmStatus,
+    pub temperature_sensor: AlarmStatus,
+    pub voltage_sensor: AlarmStatus,
+
+    /// Alarms for L1-L3
+    pub lines: [LineAlarms; 3],
+
+    pub phase_rotation: AlarmStatus,
+    pub grid_lost: AlarmStatus,
+}
+
+impl AlarmSnapshot {
+    /// Build a snapshot from decoded alarms, alarms which are in the same state
+    /// as in `prev` keep their transition time
+    pub fn new(time: DateTime<Utc>, alarms: &[Alarm], prev: Option<&AlarmSnapshot>) -> Self {
+        let ok = AlarmStatus {
+            state: AlarmState::Ok,
+            since: time,
+        };
+        let line = LineAlarms {
+            temperature: ok,
+            low_battery: ok,
+            overload: ok,
+            ripple: ok,
+        };
+        let mut ret = AlarmSnapshot {
+            time,
+            high_temperature: ok,
+            low_battery: ok,
+            overload: ok,
+            temperature_sensor: ok,
+            voltage_sensor: ok,
+            lines: [line; 3],
+            phase_rotation: ok,
+            grid_lost: ok,
+        };
+
+        for a in alarms {
+            let since = match prev.map(|p| p.get(*a)) {
+                Some(p) if p.state == a.state() => p.since,
+                _ => time,
+            };
+            *ret.get_mut(*a) = AlarmStatus {
+                state: a.state(),
+                since,
+            };
+        }
+        ret
+    }
+
+    /// Status of an alarm, the state of `alarm` is ignored
+    pub fn get(&self, alarm: Alarm) -> &AlarmStatus {
+        use Alarm::*;
+        match alarm {
+            HighTemperature(_) => &self.high_temperature,
+            LowBattery(_) => &self.low_battery,
+            Overload(_) => &self.overload,
+            TemperatureSensor(_) => &self.temperature_sensor,
+            VoltageSensor(_) => &self.voltage_sensor,
+            LineTemperature(l, _) => &self.lines[l as usize - 1].temperature,
+            LineLowBattery(l, _) => &self.lines[l as usize - 1].low_battery,
+            LineOverload(l, _) => &self.lines[l as usize - 1].overload,
+            LineRipple(l, _) => &self.lines[l as usize - 1].ripple,
+            PhaseRotation(_) => &self.phase_rotation,
+            GridLost(_) => &self.grid_lost,
+        }
+    }
+
+    fn get_mut(&mut self, alarm: Alarm) -> &mut AlarmStatus {
+        use Alarm::*;
+        match alarm {
+            HighTemperature(_) => &mut self.high_temperature,
+            LowBattery(_) => &mut self.low_battery,
+            Overload(_) => &mut self.overload,
+            TemperatureSensor(_) => &mut self.temperature_sensor,
+            VoltageSensor(_) => &mut self.voltage_sensor,
+            LineTemperature(l, _) => &mut self.lines[l as usize - 1].temperature,
+            LineLowBattery(l, _) => &mut self.lines[l as usize - 1].low_battery,
+            LineOverload(l, _) => &mut self.lines[l as usize - 1].overload,
+            LineRipple(l, _) => &mut self.lines[l as usize - 1].ripple,
+            PhaseRotation(_) => &mut self.phase_rotation,
+            GridLost(_) => &mut self.grid_lost,
+        }
+    }
+
+    /// Every alarm with its current state
+    pub fn all(&self) -> Vec<Alarm> {
+        ALL_ALARMS
+            .iter()
+            .map(|a| a.with_state(self.get(*a).state))
+            .collect()
+    }
+
+    /// Alarms which are not [AlarmState::Ok]
+    pub fn active(&self) -> Vec<Alarm> {
+        self.all()
+            .into_iter()
+            .filter(|a| a.state() != AlarmState::Ok)
+            .collect()
+    }
+
+    /// Most severe state of any alarm
+    pub fn worst(&self) -> AlarmState {
+        self.all()
+            .iter()
+            .map(|a| a.state())
+            .max()
+            .unwrap_or(AlarmState::Ok)
+    }
+}
+
+/// Alarm registers in the order they are reported by [VictronBus::get_alarms]
+const ALL_ALARMS: [Alarm; 19] = {
+    use AlarmState::Ok;
+    use Alarm::*;
+    use Line::*;
+    [
+        HighTemperature(Ok),
+        LowBattery(Ok),
+        Overload(Ok),
+        TemperatureSensor(Ok),
+        VoltageSensor(Ok),
+        LineTemperature(L1, Ok),
+        LineLowBattery(L1, Ok),
+        LineOverload(L1, Ok),
+        LineRipple(L1, Ok),
+        LineTemperature(L2, Ok),
+        LineLowBattery(L2, Ok),
+        LineOverload(L2, Ok),
+        LineRipple(L2, Ok),
+        LineTemperature(L3, Ok),
+        LineLowBattery(L3, Ok),
+        LineOverload(L3, Ok),
+        LineRipple(L3, Ok),
+        PhaseRotation(Ok),
+        GridLost(Ok),
+    ]
+};
+
 impl VictronBus {
-    pub async fn new(addr: SocketAddr, unit: u8) -> Result<Self, VictronError> {
-        let mut cli = VictronClient::new(addr).await?;
-        cli.set_unit(unit);
-        Ok(Self { client: cli })
+    pub fn new(conn: &VictronConnection, unit: u8) -> Self {
+        Self {
+            client: VictronClient::new(conn, unit),
+            alarms: None,
+        }
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.client.state().await
     }
 
     pub async fn get_line_info(
@@ -210,37 +458,79 @@ impl VictronBus {
         side: Side,
         line: Line,
     ) -> Result<LineDetail, VictronError> {
-        match side {
-            Side::Input => self.input_info(line).await,
-            Side::Output => self.output_info(line).await,
-        }
+        let block = match side {
+            Side::Input => {
+                self.read(Register::InputVoltage(Line::L1), Register::InputPower(Line::L3))
+                    .await?
+            }
+            Side::Output => {
+                self.read(Register::OutputVoltage(Line::L1), Register::OutputPower(Line::L3))
+                    .await?
+            }
+        };
+        self.line_detail(&block, side, line)
     }
 
-    async fn input_info(&mut self, line: Line) -> Result<LineDetail, VictronError> {
-        let v = self.get(Register::InputVoltage(line)).await?;
-        let i = self.get(Register::InputCurrent(line)).await?;
-        let f = self.get(Register::InputFrequency(line)).await?;
-        let p = self.get(Register::InputPower(line)).await?;
-
-        Ok(LineDetail {
-            voltage: v as f32 / 10f32,
-            current: i as f32 / 10f32,
-            frequency: f as f32 / 100f32,
-            power: p as f32 / 0.1f32,
+    /// Read every VE.Bus value in one request
+    pub async fn snapshot(&mut self) -> Result<BusSnapshot, VictronError> {
+        use crate::victron::ve_bus::Alarm as BusAlarm;
+        use AlarmState::Ok;
+        let block = self
+            .read(
+                Register::InputVoltage(Line::L1),
+                Register::Alarm(BusAlarm::GridLost(Ok)),
+            )
+            .await?;
+
+        let lines = |side| {
+            [Line::L1, Line::L2, Line::L3]
+                .iter()
+                .map(|l| self.line_detail(&block, side, *l))
+                .collect::<Result<Vec<LineDetail>, VictronError>>()
+        };
+
+        Result::Ok(BusSnapshot {
+            input: lines(Side::Input)?,
+            output: lines(Side::Output)?,
+            battery_voltage: block.u16(self.get_register(Register::BatteryVoltage)?)? as f32 / 100.0,
+            battery_current: block.i16(self.get_register(Register::BatteryCurrent)?)? as f32 / 10.0,
+            phase_count: block.u16(self.get_register(Register::PhaseCount)?)?,
+            active_input: ActiveInput::try_from(
+                block.u16(self.get_register(Register::ActiveInput)?)?,
+            )?,
+            soc: block.u16(self.get_register(Register::Soc(0f32))?)? as f32 / 10.0,
+            state: State::try_from(block.u16(self.get_register(Register::State)?)? as u8)?,
+            mode: Mode::try_from(block.u16(self.get_register(Register::Mode)?)? as u8)?,
+            alarms: self.decode_alarms(&block)?,
         })
     }
 
-    async fn output_info(&mut self, line: Line) -> Result<LineDetail, VictronError> {
-        let v = self.get(Register::OutputVoltage(line)).await?;
-        let i = self.get(Register::OutputCurrent(line)).await?;
-        let f = self.get(Register::OutputFrequency).await?;
-        let p = self.get(Register::OutputPower(line)).await?;
+    fn line_detail(
+        &self,
+        block: &RegisterBlock,
+        side: Side,
+        line: Line,
+    ) -> Result<LineDetail, VictronError> {
+        let (v, i, f, p) = match side {
+            Side::Input => (
+                Register::InputVoltage(line),
+                Register::InputCurrent(line),
+                Register::InputFrequency(line),
+                Register::InputPower(line),
+            ),
+            Side::Output => (
+                Register::OutputVoltage(line),
+                Register::OutputCurrent(line),
+                Register::OutputFrequency,
+                Register::OutputPower(line),
+            ),
+        };
 
         Ok(LineDetail {
-            voltage: v as f32 / 10f32,
-            current: i as f32 / 10f32,
-            frequency: f as f32 / 100f32,
-            power: p as f32 / 0.1f32,
+            voltage: block.u16(self.get_register(v)?)? as f32 / 10f32,
+            current: block.i16(self.get_register(i)?)? as f32 / 10f32,
+            frequency: block.i16(self.get_register(f)?)? as f32 / 100f32,
+            power: block.i16(self.get_register(p)?)? as f32 / 0.1f32,
         })
     }
 
@@ -266,69 +556,46 @@ impl VictronBus {
 
     pub async fn get_active_input(&mut self) -> Result<ActiveInput, VictronError> {
         let a = self.get(Register::ActiveInput).await?;
-        Ok(match a {
-            0 => ActiveInput::Line1,
-            1 => ActiveInput::Line2,
-            240 => ActiveInput::Disconnected,
-            e => return Err(VictronError(format!("Unknown active input {}!", e))),
-        })
+        ActiveInput::try_from(a)
     }
 
-    pub async fn get_alarms(&mut self) -> Result<Vec<Alarm>, VictronError> {
-        use crate::victron::ve_bus::Alarm::*;
-        use AlarmState::*;
-        use Line::*;
-        let mut all_alarms = vec![
-            HighTemperature(Ok),
-            LowBattery(Ok),
-            Overload(Ok),
-            TemperatureSensor(Ok),
-            VoltageSensor(Ok),
-            LineTemperature(L1, Ok),
-            LineLowBattery(L1, Ok),
-            LineOverload(L1, Ok),
-            LineRipple(L1, Ok),
-            LineTemperature(L2, Ok),
-            LineLowBattery(L2, Ok),
-            LineOverload(L2, Ok),
-            LineRipple(L2, Ok),
-            LineTemperature(L3, Ok),
-            LineLowBattery(L3, Ok),
-            LineOverload(L3, Ok),
-            LineRipple(L3, Ok),
-            PhaseRotation(Ok),
-            GridLost(Ok),
-        ];
-
-        for alarm in all_alarms.iter_mut() {
-            let sv = self
-                .client
-                .read_u16(self.get_register(Register::Alarm(alarm.clone()))?)
-                .await?;
-            let state = AlarmState::try_from(sv as u8)?;
-
-            match alarm {
-                HighTemperature(mut _v) => _v = state,
-                LowBattery(mut _v) => _v = state,
-                Overload(mut _v) => _v = state,
-                TemperatureSensor(mut _v) => _v = state,
-                VoltageSensor(mut _v) => _v = state,
-                LineTemperature(_, mut _v) => _v = state,
-                LineLowBattery(_, mut _v) => _v = state,
-                LineOverload(_, mut _v) => _v = state,
-                LineRipple(_, mut _v) => _v = state,
-                PhaseRotation(mut _v) => _v = state,
-                GridLost(mut _v) => _v = state,
-            }
-        }
+    /// Read all alarms, transition times are tracked across calls
+    pub async fn get_alarms(&mut self) -> Result<AlarmSnapshot, VictronError> {
+        use AlarmState::Ok;
+        let block = self
+            .read(
+                Register::Alarm(Alarm::HighTemperature(Ok)),
+                Register::Alarm(Alarm::GridLost(Ok)),
+            )
+            .await?;
+        self.decode_alarms(&block)
+    }
 
-        Result::Ok(all_alarms)
+    fn decode_alarms(&mut self, block: &RegisterBlock) -> Result<AlarmSnapshot, VictronError> {
+        let alarms = ALL_ALARMS
+            .iter()
+            .map(|a| {
+                let sv = block.u16(self.get_register(Register::Alarm(*a))?)?;
+                Ok(a.with_state(AlarmState::try_from(sv as u8)?))
+            })
+            .collect::<Result<Vec<Alarm>, VictronError>>()?;
+
+        let snapshot = AlarmSnapshot::new(Utc::now(), &alarms, self.alarms.as_ref());
+        self.alarms = Some(snapshot.clone());
+        Ok(snapshot)
     }
 
     pub async fn get(&mut self, reg: Register) -> Result<u16, VictronError> {
         self.client.read_u16(self.get_register(reg)?).await
     }
 
+    /// Read all registers from `first` to `last` inclusive in one request
+    async fn read(&mut self, first: Register, last: Register) -> Result<RegisterBlock, VictronError> {
+        let start = self.get_register(first)?;
+        let end = self.get_register(last)?;
+        self.client.read_block(start, end - start + 1).await
+    }
+
     fn get_register(&self, reg: Register) -> Result<u16, VictronError> {
         use crate::victron::ve_bus::Alarm as BusAlarm;
         use Register::*;
@@ -370,8 +637,107 @@ impl VictronBus {
             ACInputIgnore(l, _) => match l {
                 Line::L1 => 69,
                 Line::L2 => 70,
-                Line::L3 => return Err(VictronError("No AC Input Ignore for Line 3!".to_owned())),
+                Line::L3 => {
+                    return Err(VictronError::RegisterUnavailable {
+                        register: "AC input ignore",
+                        line: Line::L3,
+                    })
+                }
             },
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::victron::sim::{Simulator, INVERTER};
+
+    #[tokio::test]
+    async fn snapshot() {
+        let sim = Simulator::start().await;
+        sim.set_output_power(Line::L1, 1230.0);
+        sim.set(INVERTER, 27, -125i16 as u16);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bus = VictronBus::new(&conn, INVERTER);
+        let s = bus.snapshot().await.unwrap();
+
+        assert_eq!(s.output[0].power, 1230.0);
+        assert_eq!(s.output[0].voltage, 230.0);
+        assert_eq!(s.input[0].frequency, 50.0);
+        assert_eq!(s.battery_voltage, 52.0);
+        assert_eq!(s.battery_current, -12.5);
+        assert_eq!(s.soc, 80.0);
+        assert_eq!(s.state, State::Inverting);
+        assert_eq!(s.mode, Mode::On);
+        assert_eq!(s.alarms.worst(), AlarmState::Ok);
+
+        let out1 = bus.get_line_info(Side::Output, Line::L1).await.unwrap();
+        assert_eq!(out1.power, s.output[0].power);
+        assert_eq!(bus.get_mode().await.unwrap(), Mode::On);
+    }
+
+    #[tokio::test]
+    async fn unknown_value() {
+        let sim = Simulator::start().await;
+        sim.set(INVERTER, 33, 9);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bus = VictronBus::new(&conn, INVERTER);
+        assert!(matches!(
+            bus.get_mode().await,
+            Err(VictronError::UnknownValue { what: "mode", value: 9 })
+        ));
+        assert!(matches!(
+            bus.get_register(Register::ACInputIgnore(Line::L3, false)),
+            Err(VictronError::RegisterUnavailable { line: Line::L3, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn alarms() {
+        use AlarmState::Ok;
+        let sim = Simulator::start().await;
+        sim.set(INVERTER, 34, 1); // high temperature
+        sim.set(INVERTER, 50, 2); // overload L2
+        sim.set(INVERTER, 64, 2); // grid lost
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bus = VictronBus::new(&conn, INVERTER);
+        let a = bus.get_alarms().await.unwrap();
+
+        assert_eq!(a.high_temperature.state, AlarmState::Warning);
+        assert_eq!(a.lines[1].overload.state, AlarmState::Alarm);
+        assert_eq!(a.lines[0].overload.state, AlarmState::Ok);
+        assert_eq!(a.get(Alarm::GridLost(Ok)).state, AlarmState::Alarm);
+        assert_eq!(a.worst(), AlarmState::Alarm);
+
+        assert_eq!(a.active().len(), 3);
+        assert!(matches!(
+            a.active()[1],
+            Alarm::LineOverload(Line::L2, AlarmState::Alarm)
+        ));
+    }
+
+    #[tokio::test]
+    async fn alarm_transitions() {
+        let sim = Simulator::start().await;
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut bus = VictronBus::new(&conn, INVERTER);
+
+        let first = bus.get_alarms().await.unwrap();
+        let second = bus.get_alarms().await.unwrap();
+        assert_eq!(second.overload.since, first.time, "unchanged keeps first read time");
+
+        sim.set(INVERTER, 36, 1);
+        let third = bus.get_alarms().await.unwrap();
+        assert_eq!(third.overload.state, AlarmState::Warning);
+        assert_eq!(third.overload.since, third.time, "transition time");
+        assert_eq!(third.low_battery.since, first.time);
+
+        // snapshot shares the transition tracking
+        let snapshot = bus.snapshot().await.unwrap();
+        assert_eq!(snapshot.alarms.overload.since, third.time);
+    }
+}
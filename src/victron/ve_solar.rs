@@ -0,0 +1,371 @@
+use std::fmt::Display;
+
+use crate::victron::client::{ConnectionState, RegisterBlock, VictronClient, VictronConnection};
+use crate::victron::{Exception, VictronError};
+
+/// com.victronenergy.solarcharger, a DC coupled MPPT
+pub struct VictronSolarCharger {
+    client: VictronClient,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Register {
+    BatteryVoltage,
+    BatteryCurrent,
+    State,
+    PvVoltage,
+    PvCurrent,
+    YieldToday,
+    MaxPowerToday,
+    YieldYesterday,
+    MaxPowerYesterday,
+    ErrorCode,
+    PvPower,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChargerState {
+    Off = 0,
+    Fault = 2,
+    Bulk = 3,
+    Absorption = 4,
+    Float = 5,
+    Storage = 6,
+    Equalize = 7,
+    Other = 11,
+    ExternalControl = 252,
+}
+
+impl Display for ChargerState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            ChargerState::Off => "Off",
+            ChargerState::Fault => "Fault",
+            ChargerState::Bulk => "Bulk",
+            ChargerState::Absorption => "Absorption",
+            ChargerState::Float => "Float",
+            ChargerState::Storage => "Storage",
+            ChargerState::Equalize => "Equalize",
+            ChargerState::Other => "Other",
+            ChargerState::ExternalControl => "External Control",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl TryFrom<u16> for ChargerState {
+    type Error = VictronError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => ChargerState::Off,
+            2 => ChargerState::Fault,
+            3 => ChargerState::Bulk,
+            4 => ChargerState::Absorption,
+            5 => ChargerState::Float,
+            6 => ChargerState::Storage,
+            7 => ChargerState::Equalize,
+            11 => ChargerState::Other,
+            252 => ChargerState::ExternalControl,
+            value => return Err(VictronError::UnknownValue { what: "charger state", value }),
+        })
+    }
+}
+
+/// Charger error code, codes without a variant are kept as [ChargerError::Other]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChargerError {
+    None,
+    BatteryTemperatureHigh,
+    BatteryVoltageHigh,
+    BatteryTemperatureSensor,
+    ChargerTemperatureHigh,
+    ChargerOverCurrent,
+    ChargerCurrentReversed,
+    BulkTimeLimit,
+    TerminalsOverheated,
+    InputVoltageHigh,
+    InputCurrentHigh,
+    InputShutdown,
+    Other(u16),
+}
+
+impl From<u16> for ChargerError {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => ChargerError::None,
+            1 => ChargerError::BatteryTemperatureHigh,
+            2 => ChargerError::BatteryVoltageHigh,
+            3..=5 => ChargerError::BatteryTemperatureSensor,
+            17 => ChargerError::ChargerTemperatureHigh,
+            18 => ChargerError::ChargerOverCurrent,
+            19 => ChargerError::ChargerCurrentReversed,
+            20 => ChargerError::BulkTimeLimit,
+            26 => ChargerError::TerminalsOverheated,
+            33 => ChargerError::InputVoltageHigh,
+            34 => ChargerError::InputCurrentHigh,
+            38 => ChargerError::InputShutdown,
+            v => ChargerError::Other(v),
+        }
+    }
+}
+
+impl Display for ChargerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            ChargerError::None => "No error".to_owned(),
+            ChargerError::BatteryTemperatureHigh => "Battery temperature too high".to_owned(),
+            ChargerError::BatteryVoltageHigh => "Battery voltage too high".to_owned(),
+            ChargerError::BatteryTemperatureSensor => "Battery temperature sensor fault".to_owned(),
+            ChargerError::ChargerTemperatureHigh => "Charger temperature too high".to_owned(),
+            ChargerError::ChargerOverCurrent => "Charger over current".to_owned(),
+            ChargerError::ChargerCurrentReversed => "Charger current reversed".to_owned(),
+            ChargerError::BulkTimeLimit => "Bulk time limit exceeded".to_owned(),
+            ChargerError::TerminalsOverheated => "Terminals overheated".to_owned(),
+            ChargerError::InputVoltageHigh => "PV voltage too high".to_owned(),
+            ChargerError::InputCurrentHigh => "PV current too high".to_owned(),
+            ChargerError::InputShutdown => "Input shutdown".to_owned(),
+            ChargerError::Other(v) => format!("Error #{}", v),
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// All solar charger values read in a single request
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SolarSnapshot {
+    /// Battery side voltage in volts
+    pub battery_voltage: f32,
+
+    /// Charge current in amps
+    pub battery_current: f32,
+
+    pub pv_voltage: f32,
+    pub pv_current: f32,
+
+    /// PV power in watts
+    pub pv_power: f32,
+
+    /// Energy harvested in kWh
+    pub yield_today: f32,
+    pub yield_yesterday: f32,
+
+    /// Peak PV power in watts
+    pub max_power_today: f32,
+    pub max_power_yesterday: f32,
+
+    pub state: ChargerState,
+    pub error: ChargerError,
+}
+
+impl VictronSolarCharger {
+    pub fn new(conn: &VictronConnection, unit: u8) -> Self {
+        Self {
+            client: VictronClient::new(conn, unit),
+        }
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.client.state().await
+    }
+
+    /// PV power in watts
+    pub async fn pv_power(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::PvPower).await? as f32 / 10.0)
+    }
+
+    /// PV voltage in volts
+    pub async fn pv_voltage(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::PvVoltage).await? as f32 / 100.0)
+    }
+
+    /// Energy harvested today in kWh
+    pub async fn yield_today(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::YieldToday).await? as f32 / 10.0)
+    }
+
+    /// Energy harvested yesterday in kWh
+    pub async fn yield_yesterday(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::YieldYesterday).await? as f32 / 10.0)
+    }
+
+    pub async fn get_state(&mut self) -> Result<ChargerState, VictronError> {
+        ChargerState::try_from(self.get(Register::State).await?)
+    }
+
+    pub async fn get_error(&mut self) -> Result<ChargerError, VictronError> {
+        Ok(ChargerError::from(self.get(Register::ErrorCode).await?))
+    }
+
+    /// Read every solar charger value in one request
+    pub async fn snapshot(&mut self) -> Result<SolarSnapshot, VictronError> {
+        let block = self.read(Register::BatteryVoltage, Register::PvPower).await?;
+        let u = |r| block.u16(self.get_register(r));
+        let i = |r| block.i16(self.get_register(r));
+
+        Ok(SolarSnapshot {
+            battery_voltage: u(Register::BatteryVoltage)? as f32 / 100.0,
+            battery_current: i(Register::BatteryCurrent)? as f32 / 10.0,
+            pv_voltage: u(Register::PvVoltage)? as f32 / 100.0,
+            pv_current: i(Register::PvCurrent)? as f32 / 10.0,
+            pv_power: u(Register::PvPower)? as f32 / 10.0,
+            yield_today: u(Register::YieldToday)? as f32 / 10.0,
+            yield_yesterday: u(Register::YieldYesterday)? as f32 / 10.0,
+            max_power_today: u(Register::MaxPowerToday)? as f32,
+            max_power_yesterday: u(Register::MaxPowerYesterday)? as f32,
+            state: ChargerState::try_from(u(Register::State)?)?,
+            error: ChargerError::from(u(Register::ErrorCode)?),
+        })
+    }
+
+    pub async fn get(&mut self, reg: Register) -> Result<u16, VictronError> {
+        self.client.read_u16(self.get_register(reg)).await
+    }
+
+    /// Read all registers from `first` to `last` inclusive in one request
+    async fn read(&mut self, first: Register, last: Register) -> Result<RegisterBlock, VictronError> {
+        let start = self.get_register(first);
+        let end = self.get_register(last);
+        self.client.read_block(start, end - start + 1).await
+    }
+
+    fn get_register(&self, reg: Register) -> u16 {
+        match reg {
+            Register::BatteryVoltage => 771,
+            Register::BatteryCurrent => 772,
+            Register::State => 775,
+            Register::PvVoltage => 776,
+            Register::PvCurrent => 777,
+            Register::YieldToday => 784,
+            Register::MaxPowerToday => 785,
+            Register::YieldYesterday => 786,
+            Register::MaxPowerYesterday => 787,
+            Register::ErrorCode => 788,
+            Register::PvPower => 789,
+        }
+    }
+}
+
+/// Several solar chargers on the same GX
+pub struct SolarChargers {
+    chargers: Vec<(u8, VictronSolarCharger)>,
+}
+
+impl SolarChargers {
+    pub fn new(conn: &VictronConnection, units: &[u8]) -> Self {
+        Self {
+            chargers: units
+                .iter()
+                .map(|u| (*u, VictronSolarCharger::new(conn, *u)))
+                .collect(),
+        }
+    }
+
+    /// Snapshot of every charger which answered, with its unit id, in the
+    /// order of the unit ids
+    pub async fn snapshot(&mut self) -> Result<Vec<(u8, SolarSnapshot)>, VictronError> {
+        let mut ret = Vec::with_capacity(self.chargers.len());
+        for (unit, c) in &mut self.chargers {
+            match c.snapshot().await {
+                Ok(s) => ret.push((*unit, s)),
+                Err(e) if offline(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Combined PV power in watts of the chargers which answered, fails only
+    /// if none of them did
+    pub async fn total_pv_power(&mut self) -> Result<f32, VictronError> {
+        let mut total = None;
+        let mut last_err = None;
+        for (_, c) in &mut self.chargers {
+            match c.pv_power().await {
+                Ok(p) => total = Some(total.unwrap_or(0.0) + p),
+                Err(e) if offline(&e) => last_err = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+        match (total, last_err) {
+            (Some(t), _) => Ok(t),
+            (None, Some(e)) => Err(e),
+            (None, None) => Ok(0.0),
+        }
+    }
+}
+
+/// Errors from a single charger which is switched off or disconnected from
+/// the GX, the other chargers can still be read
+fn offline(e: &VictronError) -> bool {
+    matches!(
+        e,
+        VictronError::Exception(
+            Exception::GatewayTargetDevice | Exception::GatewayPathUnavailable
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::victron::sim::{Simulator, SOLAR};
+
+    #[tokio::test]
+    async fn snapshot() {
+        let sim = Simulator::start().await;
+        sim.set(SOLAR[0], 788, 33);
+        sim.set(SOLAR[0], 787, 1850);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut mppt = VictronSolarCharger::new(&conn, SOLAR[0]);
+
+        let s = mppt.snapshot().await.unwrap();
+        assert_eq!(s.pv_voltage, 120.0);
+        assert_eq!(s.pv_power, 1200.0);
+        assert_eq!(s.yield_today, 4.2);
+        assert_eq!(s.max_power_yesterday, 1850.0);
+        assert_eq!(s.state, ChargerState::Bulk);
+        assert_eq!(s.error, ChargerError::InputVoltageHigh);
+
+        sim.set(SOLAR[0], 775, 1);
+        assert!(matches!(
+            mppt.get_state().await,
+            Err(VictronError::UnknownValue { what: "charger state", value: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn total_pv_power() {
+        let sim = Simulator::start().await;
+        sim.set(SOLAR[1], 789, 35000);
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut all = SolarChargers::new(&conn, &SOLAR);
+
+        assert_eq!(all.total_pv_power().await.unwrap(), 1200.0 + 3500.0);
+        assert_eq!(all.snapshot().await.unwrap().len(), 2);
+        assert_eq!(ChargerError::from(116).to_string(), "Error #116");
+
+        // a charger missing from the GX is skipped
+        let mut all = SolarChargers::new(&conn, &[SOLAR[0], 1, SOLAR[1]]);
+        assert_eq!(all.total_pv_power().await.unwrap(), 1200.0 + 3500.0);
+        let units: Vec<u8> = all.snapshot().await.unwrap().iter().map(|s| s.0).collect();
+        assert_eq!(units, SOLAR.to_vec());
+
+        let mut none = SolarChargers::new(&conn, &[1]);
+        assert!(matches!(
+            none.total_pv_power().await,
+            Err(VictronError::Exception(Exception::GatewayTargetDevice))
+        ));
+
+        // a charger which answers with an error isn't offline
+        sim.remove(SOLAR[1], 789);
+        let mut all = SolarChargers::new(&conn, &SOLAR);
+        assert!(matches!(
+            all.total_pv_power().await,
+            Err(VictronError::Exception(Exception::IllegalDataAddress))
+        ));
+        assert!(all.snapshot().await.is_err());
+    }
+}
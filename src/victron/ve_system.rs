@@ -0,0 +1,192 @@
+use crate::victron::client::{ConnectionState, RegisterBlock, VictronClient, VictronConnection};
+use crate::victron::{Line, VictronError};
+
+/// com.victronenergy.system, the GX view of the whole installation
+pub struct VictronSystem {
+    client: VictronClient,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Register {
+    PvOnOutput(Line),
+    PvOnGrid(Line),
+    Consumption(Line),
+    Grid(Line),
+    ActiveInputSource,
+
+    BatteryVoltage,
+    BatteryCurrent,
+    BatteryPower,
+    BatterySoc,
+
+    DcPvPower,
+
+    /// ESS minimum state of charge, from com.victronenergy.settings
+    EssMinSoc,
+}
+
+/// All system values read in a single pass
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SystemSnapshot {
+    /// Grid power per line in watts, negative when feeding in
+    pub grid: [f32; 3],
+
+    /// AC consumption per line in watts, loads on both the input and output side
+    pub consumption: [f32; 3],
+
+    /// PV inverter power per line in watts
+    pub pv_on_output: [f32; 3],
+    pub pv_on_grid: [f32; 3],
+
+    /// DC coupled PV power in watts
+    pub pv_dc: f32,
+
+    /// Battery voltage in volts
+    pub battery_voltage: f32,
+
+    /// Battery current in amps, negative when discharging
+    pub battery_current: f32,
+
+    /// Battery power in watts, negative when discharging
+    pub battery_power: f32,
+
+    /// State of charge percent
+    pub battery_soc: f32,
+}
+
+impl SystemSnapshot {
+    /// Total grid power in watts
+    pub fn grid_power(&self) -> f32 {
+        self.grid.iter().sum()
+    }
+
+    /// Whole-house consumption in watts
+    pub fn consumption_power(&self) -> f32 {
+        self.consumption.iter().sum()
+    }
+
+    /// AC and DC coupled PV power in watts
+    pub fn pv_power(&self) -> f32 {
+        self.pv_on_output.iter().sum::<f32>() + self.pv_on_grid.iter().sum::<f32>() + self.pv_dc
+    }
+}
+
+impl VictronSystem {
+    pub fn new(conn: &VictronConnection, unit: u8) -> Self {
+        Self {
+            client: VictronClient::new(conn, unit),
+        }
+    }
+
+    pub async fn connection_state(&self) -> ConnectionState {
+        self.client.state().await
+    }
+
+    /// Grid power on `line` in watts, negative when feeding in
+    pub async fn grid_power(&mut self, line: Line) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Grid(line)).await? as i16 as f32)
+    }
+
+    /// AC consumption on `line` in watts
+    pub async fn consumption(&mut self, line: Line) -> Result<f32, VictronError> {
+        Ok(self.get(Register::Consumption(line)).await? as f32)
+    }
+
+    /// DC coupled PV power in watts
+    pub async fn dc_pv_power(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::DcPvPower).await? as f32)
+    }
+
+    /// ESS minimum state of charge percent
+    pub async fn ess_min_soc(&mut self) -> Result<f32, VictronError> {
+        Ok(self.get(Register::EssMinSoc).await? as f32 / 10.0)
+    }
+
+    /// Read the AC, battery and DC PV values in three requests
+    pub async fn snapshot(&mut self) -> Result<SystemSnapshot, VictronError> {
+        let ac = self
+            .read(Register::PvOnOutput(Line::L1), Register::ActiveInputSource)
+            .await?;
+        let dc = self.read(Register::BatteryVoltage, Register::BatterySoc).await?;
+        let pv_dc = self.dc_pv_power().await?;
+
+        let lines = |f: fn(Line) -> Register, signed: bool| {
+            let mut ret = [0f32; 3];
+            for (i, l) in [Line::L1, Line::L2, Line::L3].into_iter().enumerate() {
+                let addr = self.get_register(f(l));
+                ret[i] = if signed { ac.i16(addr)? as f32 } else { ac.u16(addr)? as f32 };
+            }
+            Ok::<_, VictronError>(ret)
+        };
+
+        Ok(SystemSnapshot {
+            grid: lines(Register::Grid, true)?,
+            consumption: lines(Register::Consumption, false)?,
+            pv_on_output: lines(Register::PvOnOutput, false)?,
+            pv_on_grid: lines(Register::PvOnGrid, false)?,
+            pv_dc,
+            battery_voltage: dc.u16(self.get_register(Register::BatteryVoltage))? as f32 / 10.0,
+            battery_current: dc.i16(self.get_register(Register::BatteryCurrent))? as f32 / 10.0,
+            battery_power: dc.i16(self.get_register(Register::BatteryPower))? as f32,
+            battery_soc: dc.u16(self.get_register(Register::BatterySoc))? as f32,
+        })
+    }
+
+    pub async fn get(&mut self, reg: Register) -> Result<u16, VictronError> {
+        self.client.read_u16(self.get_register(reg)).await
+    }
+
+    /// Read all registers from `first` to `last` inclusive in one request
+    async fn read(&mut self, first: Register, last: Register) -> Result<RegisterBlock, VictronError> {
+        let start = self.get_register(first);
+        let end = self.get_register(last);
+        self.client.read_block(start, end - start + 1).await
+    }
+
+    fn get_register(&self, reg: Register) -> u16 {
+        match reg {
+            Register::PvOnOutput(l) => 807 + l as u16,
+            Register::PvOnGrid(l) => 810 + l as u16,
+            Register::Consumption(l) => 816 + l as u16,
+            Register::Grid(l) => 819 + l as u16,
+            Register::ActiveInputSource => 826,
+
+            Register::BatteryVoltage => 840,
+            Register::BatteryCurrent => 841,
+            Register::BatteryPower => 842,
+            Register::BatterySoc => 843,
+
+            Register::DcPvPower => 850,
+
+            Register::EssMinSoc => 2901,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::victron::sim::{Simulator, SYSTEM};
+
+    #[tokio::test]
+    async fn snapshot() {
+        let sim = Simulator::start().await;
+        sim.set(SYSTEM, 811, 300); // PV inverter on the grid side
+        sim.set(SYSTEM, 818, 250); // L2 consumption
+        sim.set(SYSTEM, 820, -200i16 as u16); // feeding in on L1
+
+        let conn = VictronConnection::connect(sim.addr()).await.unwrap();
+        let mut sys = VictronSystem::new(&conn, SYSTEM);
+
+        let s = sys.snapshot().await.unwrap();
+        assert_eq!(s.consumption, [500.0, 250.0, 0.0]);
+        assert_eq!(s.consumption_power(), 750.0);
+        assert_eq!(s.grid_power(), -200.0);
+        assert_eq!(s.pv_power(), 300.0 + 2400.0);
+        assert_eq!(s.battery_voltage, 52.0);
+        assert_eq!(s.battery_soc, 80.0);
+
+        assert_eq!(sys.grid_power(Line::L1).await.unwrap(), -200.0);
+        assert_eq!(sys.ess_min_soc().await.unwrap(), 10.0);
+    }
+}